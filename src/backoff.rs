@@ -0,0 +1,69 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::Duration;
+
+/// Delay before the first retry of a failing object.
+const BASE: Duration = Duration::from_secs(5);
+/// Longest delay between retries, the same as the regular resync interval.
+const MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Per-object exponential backoff for transient reconcile failures.
+#[derive(Default)]
+pub struct Backoff {
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl Backoff {
+    /// Record another failure of `key` and return how long to wait before retrying.
+    pub fn next(&self, key: &str) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let attempt = failures.entry(key.to_string()).or_default();
+        *attempt = attempt.saturating_add(1);
+        jitter(delay(*attempt))
+    }
+
+    /// Forget earlier failures of `key` after it reconciled successfully.
+    pub fn reset(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/// Exponential delay for the given attempt, starting at one.
+fn delay(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    BASE.saturating_mul(factor).min(MAX)
+}
+
+/// Spread retries of objects that failed together over the upper half of the delay.
+fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    half + half.mul_f64(rand::rng().random::<f64>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_until_capped() {
+        assert_eq!(delay(1), BASE);
+        assert_eq!(delay(2), BASE * 2);
+        assert_eq!(delay(3), BASE * 4);
+        assert_eq!(delay(7), MAX);
+        assert_eq!(delay(u32::MAX), MAX);
+    }
+
+    #[test]
+    fn next_grows_per_object_and_resets() {
+        let backoff = Backoff::default();
+        for attempt in 1..10 {
+            let wait = backoff.next("default/a");
+            assert!(wait >= delay(attempt) / 2 && wait <= delay(attempt));
+        }
+        assert!(backoff.next("default/b") <= BASE);
+
+        backoff.reset("default/a");
+        assert!(backoff.next("default/a") <= BASE);
+    }
+}
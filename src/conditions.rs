@@ -0,0 +1,148 @@
+use crate::{Error, ErrorClass};
+use chrono::Utc;
+pub use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The object is reconciled and matches its spec.
+pub const READY: &str = "Ready";
+/// The controller is working towards a new spec.
+pub const PROGRESSING: &str = "Progressing";
+/// The last reconcile failed, see `lastError` for details.
+pub const DEGRADED: &str = "Degraded";
+
+/// Last reconcile error, kept on the status until a reconcile succeeds.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq)]
+pub struct LastError {
+    pub reason: String,
+    pub message: String,
+    /// Permanent errors aren't retried until the spec changes.
+    #[serde(default)]
+    pub permanent: bool,
+    /// When this error was first seen; repeats of the same error keep it.
+    pub time: Time,
+}
+
+impl LastError {
+    pub fn new(error: &Error, previous: Option<&LastError>) -> LastError {
+        LastError {
+            reason: error.reason(),
+            message: error.to_string(),
+            permanent: error.class() == ErrorClass::Permanent,
+            time: Time(Utc::now()),
+        }
+        .since(previous)
+    }
+
+    /// Keep the time of `previous` when it was the same error.
+    pub fn since(mut self, previous: Option<&LastError>) -> LastError {
+        if let Some(previous) = previous
+            && previous.reason == self.reason
+            && previous.message == self.message
+        {
+            self.time = previous.time.clone();
+        }
+        self
+    }
+}
+
+/// Insert or update the condition of `type_`.
+///
+/// The transition time is only moved when the status actually flips, as the
+/// Kubernetes API conventions expect.
+pub fn set_condition(
+    conditions: &mut Vec<Condition>,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) {
+    let status = if status { "True" } else { "False" }.to_string();
+    let message = message.into();
+
+    match conditions.iter_mut().find(|c| c.type_ == type_) {
+        Some(condition) => {
+            if condition.status != status {
+                condition.last_transition_time = Time(Utc::now());
+            }
+            condition.status = status;
+            condition.reason = reason.to_string();
+            condition.message = message;
+            condition.observed_generation = observed_generation;
+        }
+        None => conditions.push(Condition {
+            type_: type_.to_string(),
+            status,
+            reason: reason.to_string(),
+            message,
+            observed_generation,
+            last_transition_time: Time(Utc::now()),
+        }),
+    }
+}
+
+pub fn mark_progressing(
+    conditions: &mut Vec<Condition>,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) {
+    set_condition(
+        conditions,
+        PROGRESSING,
+        true,
+        "Reconciling",
+        message,
+        observed_generation,
+    );
+}
+
+pub fn mark_ready(
+    conditions: &mut Vec<Condition>,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) {
+    let message = message.into();
+    set_condition(
+        conditions,
+        READY,
+        true,
+        "Reconciled",
+        message.clone(),
+        observed_generation,
+    );
+    set_condition(
+        conditions,
+        PROGRESSING,
+        false,
+        "Reconciled",
+        message.clone(),
+        observed_generation,
+    );
+    set_condition(
+        conditions,
+        DEGRADED,
+        false,
+        "Reconciled",
+        message,
+        observed_generation,
+    );
+}
+
+pub fn mark_failed(
+    conditions: &mut Vec<Condition>,
+    error: &LastError,
+    observed_generation: Option<i64>,
+) {
+    for (type_, status) in [(READY, false), (PROGRESSING, false), (DEGRADED, true)] {
+        set_condition(
+            conditions,
+            type_,
+            status,
+            &error.reason,
+            error.message.clone(),
+            observed_generation,
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{
-    Diagnostics, GarageClient, Metrics, Settings, resources::postgresql::Client as PostgresqlClient,
+    Diagnostics, GarageClient, Metrics, S3Client, Settings,
+    backoff::Backoff,
+    resources::postgresql::{DatabasePools, Pool as PostgresqlPool},
 };
 use kube::Client as KubeClient;
 use kube::runtime::events::Recorder;
@@ -20,8 +22,14 @@ pub struct Context {
     pub metrics: Arc<Metrics>,
     /// Settings
     pub settings: Arc<Settings>,
-    /// Postgresql Clients
-    pub postgresql_clients: Arc<HashMap<String, PostgresqlClient>>,
+    /// Postgresql connection pools, one per configured server
+    pub postgresql_clients: Arc<HashMap<String, PostgresqlPool>>,
+    /// Postgresql connection pools for work inside managed databases
+    pub postgresql_databases: Arc<DatabasePools>,
     /// Garage Client
     pub garage_client: Arc<GarageClient>,
+    /// S3 client for backups, when configured
+    pub s3_client: Option<Arc<S3Client>>,
+    /// Retry backoff of failing objects
+    pub backoff: Arc<Backoff>,
 }
@@ -1,8 +1,19 @@
-use crate::{Error, GarageClient, State, resources};
-use futures::future::try_join_all;
+use crate::{
+    Error, GarageClient, S3Client, State,
+    leader::LeaderElector,
+    reconciler,
+    resources::{
+        postgresql::{
+            backup::Backup, backup_schedule::BackupSchedule, database::Database, grant::Grant,
+            restore::Restore, schema::Schema, user::User,
+        },
+        s3::{bucket::Bucket, token::Token},
+    },
+};
 use kube::client::Client;
-use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::*;
 
 pub static FINALIZER: &str = "console.tjo.cloud";
 
@@ -12,29 +23,13 @@ pub async fn run(state: State) -> Result<(), Error> {
         .await
         .expect("failed to create kube Client");
 
-    let postgresql_clients: HashMap<String, resources::postgresql::Client> =
-        try_join_all(state.settings().postgresql().iter().map(|(k, v)| async {
-            let key = k.clone();
-            let client = resources::postgresql::connect(
-                key.clone(),
-                v.host.clone(),
-                v.database.clone(),
-                v.user.clone(),
-                v.password.clone(),
-                v.sslmode.clone(),
-                v.ssl_accept_invalid_cert,
-            )
-            .await
-            .unwrap_or_else(|e| panic!("failed to connect to postgresql server {key}: {e:?}"));
-
-            Ok::<(String, resources::postgresql::Client), Error>((key, client))
-        }))
-        .await
-        .expect("failed to connect to postgresql servers")
-        .into_iter()
-        .collect();
+    let postgresql_clients = state.postgresql_clients();
 
-    let postgresql_clients = Arc::new(postgresql_clients);
+    // Servers that are down are retried in the background, everything else starts right away.
+    for pool in postgresql_clients.values() {
+        tokio::spawn(pool.clone().supervise());
+    }
+    tokio::spawn(state.postgresql_databases().supervise());
 
     let garage_client = Arc::new(
         GarageClient::new(
@@ -43,50 +38,64 @@ pub async fn run(state: State) -> Result<(), Error> {
         )
         .expect("failed to create garage client"),
     );
+    let s3_client = state
+        .settings()
+        .s3()
+        .api
+        .as_ref()
+        .map(|api| Arc::new(S3Client::new(api).expect("failed to create s3 client")));
+
+    // Every replica serves health and metrics, but only the leader reconciles.
+    let identity = std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .expect("POD_NAME or HOSTNAME must be set for leader election");
+    let elector = LeaderElector::new(kube_client.clone(), identity);
+    tokio::select! {
+        _ = elector.acquire() => {},
+        _ = shutdown_signal() => return Ok(()),
+    }
 
-    match tokio::try_join!(
-        resources::postgresql::database::run(
-            state
-                .to_context(
-                    kube_client.clone(),
-                    postgresql_clients.clone(),
-                    garage_client.clone()
-                )
-                .await,
-            kube_client.clone(),
-        ),
-        resources::postgresql::user::run(
-            state
-                .to_context(
-                    kube_client.clone(),
-                    postgresql_clients.clone(),
-                    garage_client.clone()
-                )
-                .await,
-            kube_client.clone(),
-        ),
-        resources::s3::token::run(
-            state
-                .to_context(
-                    kube_client.clone(),
-                    postgresql_clients.clone(),
-                    garage_client.clone()
-                )
-                .await,
-            kube_client.clone(),
-        ),
-        resources::s3::bucket::run(
-            state
-                .to_context(
-                    kube_client.clone(),
-                    postgresql_clients.clone(),
-                    garage_client.clone()
-                )
-                .await,
-            kube_client.clone(),
+    // Every controller gets its own context, so retries are tracked per kind.
+    let context = async || {
+        state
+            .to_context(
+                kube_client.clone(),
+                garage_client.clone(),
+                s3_client.clone(),
+            )
+            .await
+    };
+    let controllers = async {
+        tokio::try_join!(
+            reconciler::run::<Database>(context().await, kube_client.clone()),
+            reconciler::run::<User>(context().await, kube_client.clone()),
+            reconciler::run::<Token>(context().await, kube_client.clone()),
+            reconciler::run::<Bucket>(context().await, kube_client.clone()),
+            reconciler::run::<Backup>(context().await, kube_client.clone()),
+            reconciler::run::<BackupSchedule>(context().await, kube_client.clone()),
+            reconciler::run::<Restore>(context().await, kube_client.clone()),
+            reconciler::run::<Schema>(context().await, kube_client.clone()),
+            reconciler::run::<Grant>(context().await, kube_client.clone()),
         )
-    ) {
-        Ok((_, _, _, _)) => Ok(()),
-        Err(err) => Err(err),
+    };
+
+    // Controllers stop on their own on shutdown, after which the lease is handed over.
+    // Losing the lease stops the process, so a restarted replica can queue up again.
+    tokio::select! {
+        result = controllers => {
+            if let Err(err) = elector.release().await {
+                warn!("failed to release leader lease: {err:?}");
+            }
+            result.map(|_| ())
+        }
+        err = elector.hold() => Err(err),
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
 }
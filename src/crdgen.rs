@@ -5,6 +5,11 @@ fn main() {
         console::resources::s3::token::Token::crd(),
         console::resources::postgresql::database::Database::crd(),
         console::resources::postgresql::user::User::crd(),
+        console::resources::postgresql::backup::Backup::crd(),
+        console::resources::postgresql::backup_schedule::BackupSchedule::crd(),
+        console::resources::postgresql::restore::Restore::crd(),
+        console::resources::postgresql::schema::Schema::crd(),
+        console::resources::postgresql::grant::Grant::crd(),
     ];
 
     for document in documents {
@@ -4,6 +4,9 @@ use serde_json::json;
 use thiserror::Error;
 use tracing::*;
 
+mod s3;
+pub use s3::{Download, S3Client, Upload};
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Request: {0}")]
@@ -11,9 +14,28 @@ pub enum Error {
 
     #[error("StatusCode: {0} Body: {1}")]
     BadStatusCode(StatusCode, String),
+
+    #[error("BadUrl: {0}")]
+    BadUrl(String),
+
+    #[error("BadResponse: {0}")]
+    BadResponse(String),
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+impl Error {
+    /// Network failures, throttling and server errors are worth retrying.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Request(_) | Error::BadResponse(_) => true,
+            Error::BadStatusCode(status, _) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::BadUrl(_) => false,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct GarageClient {
     token: String,
@@ -24,6 +46,8 @@ pub struct GarageClient {
 #[derive(Deserialize)]
 pub struct Bucket {
     pub id: String,
+    #[serde(default, alias = "globalAliases")]
+    pub global_aliases: Vec<String>,
 }
 
 #[derive(Deserialize)]
@@ -35,7 +59,28 @@ pub struct Key {
     pub secret: String,
 }
 
-#[derive(Clone)]
+/// Entry of the key list, without permissions or secret.
+#[derive(Deserialize)]
+pub struct KeySummary {
+    pub name: String,
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct KeyInfo {
+    pub name: String,
+    #[serde(alias = "accessKeyId")]
+    pub id: String,
+    pub buckets: Vec<KeyBucket>,
+}
+
+#[derive(Deserialize)]
+pub struct KeyBucket {
+    pub id: String,
+    pub permissions: BucketPermissions,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct BucketPermissions {
     pub owner: bool,
     pub read: bool,
@@ -104,11 +149,65 @@ impl GarageClient {
         }
     }
 
+    pub async fn get_bucket(&self, id: String) -> Result<Option<Bucket>, Error> {
+        let response = self
+            .http_client
+            .clone()
+            .get(format!("{0}/v2/GetBucketInfo", self.url))
+            .query(&[("id", &id)])
+            .bearer_auth(self.token.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if status_code == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                res.json::<Bucket>().await.map(Some).map_err(Error::Request)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    /// Bucket behind the global alias `global_alias`, `None` when the alias isn't taken.
+    pub async fn get_bucket_by_alias(&self, global_alias: String) -> Result<Option<Bucket>, Error> {
+        let response = self
+            .http_client
+            .clone()
+            .get(format!("{0}/v2/GetBucketInfo", self.url))
+            .query(&[("globalAlias", &global_alias)])
+            .bearer_auth(self.token.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if status_code == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                res.json::<Bucket>().await.map(Some).map_err(Error::Request)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
     pub async fn delete_bucket(&self, id: String) -> Result<(), Error> {
         let response = self
             .http_client
             .clone()
-            .post(format!("{0}/v2/DeleteBucket?id={1}", self.url, id))
+            .post(format!("{0}/v2/DeleteBucket", self.url))
+            .query(&[("id", &id)])
             .bearer_auth(self.token.clone())
             .send()
             .await;
@@ -126,6 +225,62 @@ impl GarageClient {
         }
     }
 
+    pub async fn add_bucket_alias(&self, id: String, global_alias: String) -> Result<(), Error> {
+        let body = json!({
+            "bucketId": id,
+            "globalAlias": global_alias,
+        });
+
+        let response = self
+            .http_client
+            .clone()
+            .post(format!("{0}/v2/AddBucketAlias", self.url))
+            .bearer_auth(self.token.clone())
+            .json(&body)
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    pub async fn remove_bucket_alias(&self, id: String, global_alias: String) -> Result<(), Error> {
+        let body = json!({
+            "bucketId": id,
+            "globalAlias": global_alias,
+        });
+
+        let response = self
+            .http_client
+            .clone()
+            .post(format!("{0}/v2/RemoveBucketAlias", self.url))
+            .bearer_auth(self.token.clone())
+            .json(&body)
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
     pub async fn create_key(&self, name: String) -> Result<Key, Error> {
         let body = json!({
             "allow" : {
@@ -160,11 +315,118 @@ impl GarageClient {
         }
     }
 
+    pub async fn get_key(&self, id: String) -> Result<Option<KeyInfo>, Error> {
+        let response = self
+            .http_client
+            .clone()
+            .get(format!("{0}/v2/GetKeyInfo", self.url))
+            .query(&[("id", &id)])
+            .bearer_auth(self.token.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if status_code == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                res.json::<KeyInfo>()
+                    .await
+                    .map(Some)
+                    .map_err(Error::Request)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    /// Key together with its secret, `None` when it doesn't exist.
+    pub async fn get_key_with_secret(&self, id: String) -> Result<Option<Key>, Error> {
+        let response = self
+            .http_client
+            .clone()
+            .get(format!("{0}/v2/GetKeyInfo", self.url))
+            .query(&[("id", id.as_str()), ("showSecretKey", "true")])
+            .bearer_auth(self.token.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if status_code == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                res.json::<Key>().await.map(Some).map_err(Error::Request)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    pub async fn list_keys(&self) -> Result<Vec<KeySummary>, Error> {
+        let response = self
+            .http_client
+            .clone()
+            .get(format!("{0}/v2/ListKeys", self.url))
+            .bearer_auth(self.token.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                res.json::<Vec<KeySummary>>().await.map_err(Error::Request)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    pub async fn update_key_name(&self, id: String, name: String) -> Result<(), Error> {
+        let body = json!({
+            "name": name,
+        });
+
+        let response = self
+            .http_client
+            .clone()
+            .post(format!("{0}/v2/UpdateKey", self.url))
+            .query(&[("id", &id)])
+            .bearer_auth(self.token.clone())
+            .json(&body)
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
     pub async fn delete_key(&self, id: String) -> Result<(), Error> {
         let response = self
             .http_client
             .clone()
-            .post(format!("{0}/v2/DeleteKey?id={1}", self.url, id))
+            .post(format!("{0}/v2/DeleteKey", self.url))
+            .query(&[("id", &id)])
             .bearer_auth(self.token.clone())
             .send()
             .await;
@@ -226,6 +488,28 @@ impl GarageClient {
         }
     }
 
+    /// Grant `permissions` without taking away any the key already has.
+    pub async fn allow_bucket_permissions(
+        &self,
+        bucket_id: String,
+        key_id: String,
+        permissions: BucketPermissions,
+    ) -> Result<(), Error> {
+        self.bucket_key_permissions(bucket_id, key_id, permissions, PermissionKind::Allow)
+            .await
+    }
+
+    /// Take away `permissions`, leaving the others the key has.
+    pub async fn deny_bucket_permissions(
+        &self,
+        bucket_id: String,
+        key_id: String,
+        permissions: BucketPermissions,
+    ) -> Result<(), Error> {
+        self.bucket_key_permissions(bucket_id, key_id, permissions, PermissionKind::Deny)
+            .await
+    }
+
     pub async fn set_bucket_permissions(
         &self,
         bucket_id: String,
@@ -0,0 +1,425 @@
+//! Minimal client for Garage's S3 API, signed with AWS Signature Version 4.
+//!
+//! It only covers what backups and restores need: streaming multipart uploads,
+//! streaming downloads and deletes.
+use super::{BucketPermissions, Error, GarageClient, Result};
+use crate::settings::S3Api;
+use bytes::Bytes;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Client, Method, Response, Url, redirect};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::*;
+
+/// Everything but the unreserved characters is escaped in canonical URIs and queries.
+const URI_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Buffered bytes before a part is sent, S3 wants at least 5 MiB for every part but the last.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// What the service key needs on a bucket to upload, download and delete dumps.
+const READ_WRITE: BucketPermissions = BucketPermissions {
+    owner: false,
+    read: true,
+    write: true,
+};
+
+static USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone)]
+pub struct S3Client {
+    url: Url,
+    host: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    http_client: Client,
+    /// Buckets the service key may use right now, with the number of operations using each.
+    buckets: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl S3Client {
+    pub fn new(settings: &S3Api) -> Result<S3Client, Error> {
+        let url = Url::parse(settings.url.trim_end_matches('/'))
+            .map_err(|err| Error::BadUrl(err.to_string()))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::BadUrl(settings.url.clone())),
+        };
+
+        let http_client = reqwest::ClientBuilder::new()
+            .user_agent(USER_AGENT)
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(Error::Request)?;
+
+        info!(
+            "Created new S3 client: url={url} region={0}",
+            settings.region
+        );
+
+        Ok(S3Client {
+            url,
+            host,
+            region: settings.region.clone(),
+            access_key_id: settings.access_key_id.clone(),
+            secret_access_key: settings.secret_access_key.trim().to_string(),
+            http_client,
+            buckets: Arc::default(),
+        })
+    }
+
+    /// Run `work` with the service key allowed to read and write the bucket `bucket_id`.
+    ///
+    /// The permission is only held while some operation needs it, the last one to finish
+    /// revokes it again, so the key doesn't keep access to every bucket it ever touched.
+    pub async fn with_bucket<T, E: From<Error>>(
+        &self,
+        garage: &GarageClient,
+        bucket_id: &str,
+        work: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        {
+            let mut buckets = self.buckets.lock().await;
+            let holders = buckets.entry(bucket_id.to_string()).or_default();
+            if *holders == 0 {
+                garage
+                    .allow_bucket_permissions(
+                        bucket_id.to_string(),
+                        self.access_key_id.clone(),
+                        READ_WRITE,
+                    )
+                    .await?;
+            }
+            *holders += 1;
+        }
+
+        let result = work.await;
+
+        let mut buckets = self.buckets.lock().await;
+        if let Some(holders) = buckets.get_mut(bucket_id) {
+            *holders -= 1;
+            if *holders == 0 {
+                buckets.remove(bucket_id);
+                if let Err(err) = garage
+                    .deny_bucket_permissions(
+                        bucket_id.to_string(),
+                        self.access_key_id.clone(),
+                        READ_WRITE,
+                    )
+                    .await
+                {
+                    warn!("failed to revoke access to bucket {bucket_id}: {err:?}");
+                }
+            }
+        }
+        result
+    }
+
+    /// Send a path-style request for `bucket`/`key`, signed with the service key.
+    async fn send(
+        &self,
+        method: Method,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+
+        let mut path = format!("/{}", encode(bucket));
+        for segment in key.split('/') {
+            path.push('/');
+            path.push_str(&encode(segment));
+        }
+        let mut query: Vec<(String, String)> =
+            query.iter().map(|(k, v)| (encode(k), encode(v))).collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{0}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}",
+            self.host
+        );
+        let (scope, signature) = sign(
+            &self.secret_access_key,
+            &amz_date,
+            &self.region,
+            &canonical_request,
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={0}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+            self.access_key_id
+        );
+
+        let mut url = format!("{}{path}", self.url.as_str().trim_end_matches('/'));
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let response = self
+            .http_client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await;
+
+        match response {
+            Ok(res) => {
+                let status_code = res.status();
+                if !status_code.is_success() {
+                    let content = res.text().await.map_err(Error::Request)?;
+                    return Err(Error::BadStatusCode(status_code, content));
+                }
+                Ok(res)
+            }
+            Err(err) => Err(Error::Request(err)),
+        }
+    }
+
+    /// Start a multipart upload of `key`, parts are sent as data is written.
+    pub async fn upload(&self, bucket: &str, key: &str) -> Result<Upload, Error> {
+        let response = self
+            .send(Method::POST, bucket, key, &[("uploads", "")], Vec::new())
+            .await?;
+        let body = response.text().await.map_err(Error::Request)?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| Error::BadResponse(body.clone()))?
+            .to_string();
+
+        Ok(Upload {
+            client: self.clone(),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id,
+            buffer: Vec::with_capacity(PART_SIZE),
+            parts: Vec::new(),
+            size: 0,
+        })
+    }
+
+    /// Start downloading `key`, the body is read as it arrives.
+    pub async fn download(&self, bucket: &str, key: &str) -> Result<Download, Error> {
+        let response = self.send(Method::GET, bucket, key, &[], Vec::new()).await?;
+        Ok(Download { response })
+    }
+
+    pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, bucket, key, &[], Vec::new())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Object being downloaded, read it with `chunk` until it returns `None`.
+pub struct Download {
+    response: Response,
+}
+
+impl Download {
+    /// Size of the whole object, if the server sent it.
+    pub fn size(&self) -> Option<u64> {
+        self.response.content_length()
+    }
+
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, Error> {
+        self.response.chunk().await.map_err(Error::Request)
+    }
+}
+
+/// Multipart upload in progress, either `complete` or `abort` it.
+pub struct Upload {
+    client: S3Client,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    buffer: Vec<u8>,
+    /// Part numbers with their ETags, needed to complete the upload.
+    parts: Vec<(usize, String)>,
+    size: u64,
+}
+
+impl Upload {
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.buffer.extend_from_slice(data);
+        self.size += data.len() as u64;
+        if self.buffer.len() >= PART_SIZE {
+            self.send_part().await?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn send_part(&mut self) -> Result<(), Error> {
+        let number = self.parts.len() + 1;
+        let body = std::mem::replace(&mut self.buffer, Vec::with_capacity(PART_SIZE));
+        let response = self
+            .client
+            .send(
+                Method::PUT,
+                &self.bucket,
+                &self.key,
+                &[
+                    ("partNumber", &number.to_string()),
+                    ("uploadId", &self.upload_id),
+                ],
+                body,
+            )
+            .await?;
+        let etag = response
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| Error::BadResponse(format!("part {number} has no ETag")))?
+            .to_string();
+        self.parts.push((number, etag));
+        Ok(())
+    }
+
+    /// Send what is still buffered and assemble the object, returning its size.
+    pub async fn complete(mut self) -> Result<u64, Error> {
+        // An upload needs at least one part, even if it is empty.
+        if !self.buffer.is_empty() || self.parts.is_empty() {
+            self.send_part().await?;
+        }
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in &self.parts {
+            body.push_str(&format!(
+                "<Part><PartNumber>{number}</PartNumber><ETag>{etag}</ETag></Part>"
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        let response = self
+            .client
+            .send(
+                Method::POST,
+                &self.bucket,
+                &self.key,
+                &[("uploadId", &self.upload_id)],
+                body.into_bytes(),
+            )
+            .await?;
+        // Failures can still be reported with a 200, once the response has started.
+        let body = response.text().await.map_err(Error::Request)?;
+        if xml_value(&body, "Code").is_some() {
+            return Err(Error::BadResponse(body));
+        }
+
+        Ok(self.size)
+    }
+
+    /// Throw away the parts uploaded so far.
+    pub async fn abort(self) -> Result<(), Error> {
+        self.client
+            .send(
+                Method::DELETE,
+                &self.bucket,
+                &self.key,
+                &[("uploadId", &self.upload_id)],
+                Vec::new(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, URI_ENCODE).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Sign a canonical request, returning the credential scope and the signature.
+fn sign(
+    secret_access_key: &str,
+    amz_date: &str,
+    region: &str,
+    canonical_request: &str,
+) -> (String, String) {
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac(format!("AWS4{secret_access_key}").as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    (scope, hex(&hmac(&key, &string_to_sign)))
+}
+
+/// Text of the first `<tag>` element, enough for the few responses read here.
+fn xml_value<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + body[start..].find(&format!("</{tag}>"))?;
+    Some(&body[start..end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_matches_aws_example() {
+        // GET Object example from the AWS Signature Version 4 documentation.
+        let canonical_request = "GET\n/test.txt\n\nhost:examplebucket.s3.amazonaws.com\nrange:bytes=0-9\nx-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\nx-amz-date:20130524T000000Z\n\nhost;range;x-amz-content-sha256;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let (scope, signature) = sign(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "20130524T000000Z",
+            "us-east-1",
+            canonical_request,
+        );
+        assert_eq!(scope, "20130524/us-east-1/s3/aws4_request");
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn encode_keeps_only_unreserved_characters() {
+        assert_eq!(encode("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(encode("a b/c+d=e"), "a%20b%2Fc%2Bd%3De");
+    }
+
+    #[test]
+    fn xml_value_reads_first_element() {
+        let body = "<InitiateMultipartUploadResult><Bucket>b</Bucket><UploadId>abc</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(xml_value(body, "UploadId"), Some("abc"));
+        assert_eq!(xml_value(body, "Code"), None);
+    }
+}
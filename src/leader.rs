@@ -0,0 +1,196 @@
+//! Lease based leader election, so only one replica reconciles at a time.
+//!
+//! Follows the client-go algorithm: the leader renews a `coordination.k8s.io`
+//! Lease, everyone else waits until they saw it go unrenewed for its duration,
+//! on their own clock, before taking it over. Updates are guarded by the
+//! lease's resourceVersion, so two replicas can't both win.
+use crate::{Error, Result};
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::{
+    Client,
+    api::{Api, ObjectMeta, PostParams},
+};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant, sleep, timeout};
+use tracing::*;
+
+/// Name of the Lease, in the namespace console runs in.
+const LEASE_NAME: &str = "console-tjo-cloud";
+/// How long a lease is valid without being renewed.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+/// The leader gives up when it couldn't renew for this long, well before others take over.
+const RENEW_DEADLINE: Duration = Duration::from_secs(10);
+/// Interval of acquire and renew attempts.
+const RETRY_PERIOD: Duration = Duration::from_secs(2);
+
+/// Holder and renew time of the lease, which change whenever its holder renews it.
+type Record = (Option<String>, Option<MicroTime>);
+
+pub struct LeaderElector {
+    api: Api<Lease>,
+    identity: String,
+    /// Record last read from the lease, with when it last changed by this replica's clock.
+    observed: Mutex<Option<(Record, Instant)>>,
+}
+
+impl LeaderElector {
+    pub fn new(kube_client: Client, identity: String) -> LeaderElector {
+        let api = Api::namespaced(kube_client.clone(), kube_client.default_namespace());
+        LeaderElector {
+            api,
+            identity,
+            observed: Mutex::new(None),
+        }
+    }
+
+    /// When `record` was first seen, clocks of other replicas may be off so their times aren't compared.
+    fn observe(&self, record: Record) -> Instant {
+        let mut observed = self.observed.lock().unwrap();
+        match &*observed {
+            Some((seen, at)) if *seen == record => *at,
+            _ => {
+                let at = Instant::now();
+                *observed = Some((record, at));
+                at
+            }
+        }
+    }
+
+    /// Wait until this replica holds the lease.
+    pub async fn acquire(&self) {
+        info!(
+            "Waiting for leader lease {LEASE_NAME} identity={}",
+            self.identity
+        );
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!(
+                        "Acquired leader lease {LEASE_NAME} identity={}",
+                        self.identity
+                    );
+                    return;
+                }
+                Ok(false) => {}
+                Err(err) => warn!("failed to acquire leader lease: {err:?}"),
+            }
+            sleep(RETRY_PERIOD).await;
+        }
+    }
+
+    /// Keep renewing the lease, returns once leadership is lost.
+    pub async fn hold(&self) -> Error {
+        let mut renewed = Instant::now();
+        loop {
+            sleep(RETRY_PERIOD).await;
+            // A renew still hanging at the deadline could land after another replica took over.
+            let deadline = RENEW_DEADLINE.saturating_sub(renewed.elapsed());
+            match timeout(deadline, self.try_acquire_or_renew()).await {
+                Ok(Ok(true)) => renewed = Instant::now(),
+                Ok(Ok(false)) => break,
+                Ok(Err(err)) => {
+                    warn!("failed to renew leader lease: {err:?}");
+                    if renewed.elapsed() > RENEW_DEADLINE {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    warn!("renewing leader lease timed out");
+                    break;
+                }
+            }
+        }
+        error!("Lost leader lease {LEASE_NAME} identity={}", self.identity);
+        Error::LeaderElectionLost
+    }
+
+    /// Hand the lease over right away instead of letting it expire.
+    pub async fn release(&self) -> Result<()> {
+        let Some(mut lease) = self
+            .api
+            .get_opt(LEASE_NAME)
+            .await
+            .map_err(Error::KubeError)?
+        else {
+            return Ok(());
+        };
+        let spec = lease.spec.get_or_insert_default();
+        if spec.holder_identity.as_ref() != Some(&self.identity) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(MicroTime(Utc::now()));
+        self.api
+            .replace(LEASE_NAME, &PostParams::default(), &lease)
+            .await
+            .map_err(Error::KubeError)?;
+        info!(
+            "Released leader lease {LEASE_NAME} identity={}",
+            self.identity
+        );
+        Ok(())
+    }
+
+    /// Take or renew the lease, `false` when someone else holds it.
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = Utc::now();
+
+        let Some(mut lease) = self
+            .api
+            .get_opt(LEASE_NAME)
+            .await
+            .map_err(Error::KubeError)?
+        else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(LEASE_NAME.to_string()),
+                    ..ObjectMeta::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.identity.clone()),
+                    lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                    ..LeaseSpec::default()
+                }),
+            };
+            return conflict_is_lost(self.api.create(&PostParams::default(), &lease).await);
+        };
+
+        let spec = lease.spec.get_or_insert_default();
+        let holder = spec.holder_identity.clone().unwrap_or_default();
+        if holder != self.identity {
+            // The lease expires once nobody renewed it for its duration, as measured here.
+            let seen = self.observe((spec.holder_identity.clone(), spec.renew_time.clone()));
+            let duration =
+                Duration::from_secs(spec.lease_duration_seconds.unwrap_or(0).max(0) as u64);
+            if !holder.is_empty() && seen.elapsed() < duration {
+                return Ok(false);
+            }
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.lease_duration_seconds = Some(LEASE_DURATION.as_secs() as i32);
+        spec.renew_time = Some(MicroTime(now));
+
+        // The resourceVersion from the read makes this fail if anyone else got there first.
+        conflict_is_lost(
+            self.api
+                .replace(LEASE_NAME, &PostParams::default(), &lease)
+                .await,
+        )
+    }
+}
+
+fn conflict_is_lost(result: kube::Result<Lease>) -> Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(status)) if status.code == 409 => Ok(false),
+        Err(err) => Err(Error::KubeError(err)),
+    }
+}
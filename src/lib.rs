@@ -20,6 +20,26 @@ pub enum Error {
     // NB: Same as above?
     ControllerError(#[source] Box<kube::runtime::controller::Error<Error, Error>>),
 
+    #[error(
+        "AlreadyExists: {0} exists but was not created by console, set the console.tjo.cloud/adopt annotation to take it over"
+    )]
+    AlreadyExists(String),
+
+    #[error("AlreadyManaged: {0}")]
+    AlreadyManaged(String),
+
+    #[error("AdoptionFailed: {0}")]
+    AdoptionFailed(String),
+
+    #[error("IllegalName: {0} is longer than 63 bytes")]
+    IllegalName(String),
+
+    #[error("NameCollision: {0}")]
+    NameCollision(String),
+
+    #[error("AccessDenied: {0}")]
+    AccessDenied(String),
+
     #[error("IllegalDocument")]
     IllegalDocument,
 
@@ -35,12 +55,53 @@ pub enum Error {
     #[error("PostgresqlIllegalUser")]
     PostgresqlIllegalUser,
 
+    #[error("PostgresqlIllegalIdentifier: {0:?}")]
+    PostgresqlIllegalIdentifier(String),
+
+    #[error("PostgresqlIllegalLiteral")]
+    PostgresqlIllegalLiteral,
+
+    #[error("PostgresqlIllegalSslMode: {0}")]
+    PostgresqlIllegalSslMode(String),
+
+    #[error("PostgresqlTimeout")]
+    PostgresqlTimeout,
+
+    #[error("PostgresqlServerUnavailable: {0}")]
+    PostgresqlServerUnavailable(String),
+
     #[error("PostgresqlUnknownServer")]
     PostgresqlUnknownServer,
 
     #[error("PostgresqlUserAndDatabaseServerNotMatching")]
     PostgresqlUserAndDatabaseServerNotMatching,
 
+    #[error("PostgresqlExtensionNotAllowed: {0} is not allowed on this server")]
+    PostgresqlExtensionNotAllowed(String),
+
+    #[error("IllegalSchedule: {0}")]
+    IllegalSchedule(String),
+
+    #[error("IllegalDump: {0}")]
+    IllegalDump(String),
+
+    #[error("DumpUnsupported: {0} can't be dumped")]
+    DumpUnsupported(String),
+
+    #[error(
+        "RestoreTargetNotEmpty: database {0} already has tables, set overwrite to replace them"
+    )]
+    RestoreTargetNotEmpty(String),
+
+    #[error("NotReady: {0} is not ready yet")]
+    NotReady(String),
+
+    #[error("S3NotConfigured: s3.api has to be set in settings")]
+    S3NotConfigured,
+
+    #[error("LeaderElectionLost")]
+    LeaderElectionLost,
+
     #[error("StdIoError")]
     StdIoError(#[from] std::io::Error),
 
@@ -49,10 +110,82 @@ pub enum Error {
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How a failed reconcile is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Retried with per-object exponential backoff.
+    Transient,
+    /// Retrying won't help, so it waits for the spec to change.
+    Permanent,
+    /// Depends on something outside the resource, like namespace labels, so it's checked again now and then.
+    External,
+}
+
 impl Error {
+    /// Error raised by the reconciler itself, without the finalizer wrapping.
+    fn inner(&self) -> &Error {
+        use kube::runtime::finalizer::Error as Finalizer;
+        match self {
+            Error::FinalizerError(err) => match err.as_ref() {
+                Finalizer::ApplyFailed(err) | Finalizer::CleanupFailed(err) => err.inner(),
+                _ => self,
+            },
+            _ => self,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        use kube::runtime::finalizer::Error as Finalizer;
+        match self {
+            Error::FinalizerError(err) => match err.as_ref() {
+                Finalizer::ApplyFailed(err) | Finalizer::CleanupFailed(err) => err.class(),
+                Finalizer::UnnamedObject | Finalizer::InvalidFinalizer => ErrorClass::Permanent,
+                _ => ErrorClass::Transient,
+            },
+            Error::SerializationError(_)
+            | Error::IllegalDocument
+            | Error::AlreadyExists(_)
+            | Error::AlreadyManaged(_)
+            | Error::AdoptionFailed(_)
+            | Error::IllegalName(_)
+            | Error::PostgresqlIllegalDatabase
+            | Error::PostgresqlIllegalUser
+            | Error::PostgresqlIllegalIdentifier(_)
+            | Error::PostgresqlIllegalLiteral
+            | Error::PostgresqlIllegalSslMode(_)
+            | Error::PostgresqlUnknownServer
+            | Error::PostgresqlUserAndDatabaseServerNotMatching
+            | Error::PostgresqlExtensionNotAllowed(_)
+            | Error::IllegalSchedule(_)
+            | Error::IllegalDump(_)
+            | Error::DumpUnsupported(_)
+            | Error::RestoreTargetNotEmpty(_)
+            | Error::S3NotConfigured => ErrorClass::Permanent,
+            Error::PostgresqlClientError(err) => match err.code() {
+                // Syntax errors, access rule violations and bad data won't fix themselves.
+                Some(code) if code.code().starts_with("42") || code.code().starts_with("22") => {
+                    ErrorClass::Permanent
+                }
+                _ => ErrorClass::Transient,
+            },
+            Error::GarageClientError(err) if !err.is_transient() => ErrorClass::Permanent,
+            Error::AccessDenied(_) => ErrorClass::External,
+            _ => ErrorClass::Transient,
+        }
+    }
+
     pub fn metric_label(&self) -> String {
         format!("{self:?}").to_lowercase()
     }
+
+    /// Variant name, used as the reason of status conditions.
+    pub fn reason(&self) -> String {
+        format!("{:?}", self.inner())
+            .split(['(', ' ', '{'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
 }
 
 /// Expose all controller components used by main
@@ -73,6 +206,18 @@ pub mod resources;
 mod settings;
 pub use settings::Settings;
 
+/// Retry backoff
+pub mod backoff;
+
+/// Status conditions
+pub mod conditions;
+
+/// Generic reconciler shared by all resources
+pub mod reconciler;
+
+/// Leader election
+mod leader;
+
 /// State
 mod state;
 pub use state::*;
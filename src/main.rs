@@ -19,6 +19,19 @@ async fn health(_: HttpRequest) -> impl Responder {
     HttpResponse::Ok().json("healthy")
 }
 
+#[get("/health/postgresql")]
+async fn health_postgresql(c: Data<State>, _req: HttpRequest) -> impl Responder {
+    let servers = c.postgresql_health();
+    let healthy = servers
+        .values()
+        .all(|server| server.state == console::resources::postgresql::HealthState::Healthy);
+    if healthy {
+        HttpResponse::Ok().json(&servers)
+    } else {
+        HttpResponse::ServiceUnavailable().json(&servers)
+    }
+}
+
 #[get("/")]
 async fn index(c: Data<State>, _req: HttpRequest) -> impl Responder {
     let d = c.diagnostics().await;
@@ -52,6 +65,7 @@ async fn main() -> anyhow::Result<()> {
             .wrap(middleware::Logger::default().exclude("/health"))
             .service(index)
             .service(health)
+            .service(health_postgresql)
             .service(metrics)
     })
     .bind("0.0.0.0:8080")?
@@ -2,7 +2,7 @@ use crate::Error;
 use opentelemetry::trace::TraceId;
 use prometheus_client::{
     encoding::EncodeLabelSet,
-    metrics::{counter::Counter, exemplar::HistogramWithExemplars, family::Family},
+    metrics::{counter::Counter, exemplar::HistogramWithExemplars, family::Family, gauge::Gauge},
     registry::{Registry, Unit},
 };
 use std::sync::Arc;
@@ -11,6 +11,7 @@ use tokio::time::Instant;
 #[derive(Clone)]
 pub struct Metrics {
     pub reconcile: ReconcileMetrics,
+    pub backup: BackupMetrics,
     pub registry: Arc<Registry>,
 }
 
@@ -18,9 +19,11 @@ impl Default for Metrics {
     fn default() -> Self {
         let mut registry = Registry::with_prefix("controller");
         let reconcile = ReconcileMetrics::default().register(&mut registry);
+        let backup = BackupMetrics::default().register(registry.sub_registry_with_prefix("backup"));
         Self {
             registry: Arc::new(registry),
             reconcile,
+            backup,
         }
     }
 }
@@ -106,6 +109,56 @@ impl ReconcileMetrics {
     }
 }
 
+#[derive(Clone, Default)]
+pub struct BackupMetrics {
+    pub last_success: Family<BackupLabels, Gauge>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BackupLabels {
+    pub namespace: String,
+    pub schedule: String,
+    pub database: String,
+}
+
+impl BackupMetrics {
+    /// Register backup metrics to start tracking them.
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register_with_unit(
+            "last_success_timestamp",
+            "completion time of the newest successful scheduled backup",
+            Unit::Seconds,
+            self.last_success.clone(),
+        );
+        self
+    }
+
+    pub fn set_last_success(
+        &self,
+        namespace: String,
+        schedule: String,
+        database: String,
+        timestamp: i64,
+    ) {
+        self.last_success
+            .get_or_create(&BackupLabels {
+                namespace,
+                schedule,
+                database,
+            })
+            .set(timestamp);
+    }
+
+    /// Drop the series of a deleted schedule.
+    pub fn remove_last_success(&self, namespace: String, schedule: String, database: String) {
+        self.last_success.remove(&BackupLabels {
+            namespace,
+            schedule,
+            database,
+        });
+    }
+}
+
 /// Smart function duration measurer
 ///
 /// Relies on Drop to calculate duration and register the observation in the histogram
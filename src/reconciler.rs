@@ -0,0 +1,731 @@
+//! Generic driver shared by every resource the console manages.
+//!
+//! A resource only describes its backend object through [`Managed`]; tracing,
+//! metrics, the finalizer, events and status conditions are handled here.
+use crate::{
+    Context, Error, ErrorClass, FINALIZER, Result,
+    conditions::{Condition, LastError, mark_failed, mark_progressing, mark_ready, set_condition},
+    resources::postgresql::HealthState,
+    settings::Settings,
+    telemetry,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    Resource,
+    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
+    client::Client as KubeClient,
+    core::{NamespaceResourceScope, object::HasStatus},
+    runtime::{
+        controller::{Action, Controller},
+        events::{Event, EventType},
+        finalizer::{Event as Finalizer, finalizer},
+        watcher::Config,
+    },
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::*;
+
+/// Annotation that takes over an existing backend object, instead of failing because it exists.
+pub const ADOPT_ANNOTATION: &str = "console.tjo.cloud/adopt";
+
+/// Where the object behind a resource lives.
+pub enum Backend {
+    /// One of the servers configured in `Settings::postgresql`.
+    Postgresql(String),
+    /// The Garage cluster behind s3.tjo.cloud.
+    Garage,
+}
+
+/// What happens to the backend object when its resource is deleted.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, JsonSchema)]
+pub enum DeletionPolicy {
+    /// Delete the backend object together with the resource.
+    #[default]
+    Delete,
+    /// Keep the backend object and tag it as orphaned.
+    Retain,
+}
+
+// Status fields every managed resource carries, flattened into its status.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileStatus {
+    #[serde(default)]
+    pub created: bool,
+    /// The backend object existed before and was taken over through the adopt annotation.
+    #[serde(default)]
+    pub adopted: bool,
+    /// The backend object carries the ownership marker, objects created before markers get it once.
+    #[serde(default)]
+    pub marked: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<LastError>,
+}
+
+pub trait ManagedStatus: Default + Clone + Serialize + Send + Sync {
+    fn reconcile(&self) -> &ReconcileStatus;
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus;
+}
+
+/// A Kubernetes resource that provisions an object on one of the backends.
+pub trait Managed:
+    Resource<DynamicType = (), Scope = NamespaceResourceScope>
+    + HasStatus<Status: ManagedStatus>
+    + Clone
+    + Debug
+    + DeserializeOwned
+    + Serialize
+    + Send
+    + Sync
+    + 'static
+{
+    /// Backend object as read back from the server.
+    type Observed: Send;
+
+    fn backend(&self) -> Backend;
+
+    /// Policy set on the spec, `None` falls back to the controller default.
+    fn deletion_policy(&self) -> Option<DeletionPolicy>;
+
+    /// Reject specs that can never be reconciled.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// How long until the object is checked again when nothing changes.
+    fn requeue_after(&self, _status: &Self::Status) -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    /// Backend object the resource manages, `None` for resources that can't adopt existing objects.
+    ///
+    /// Two resources with the same identity would manage the same object.
+    fn identity(&self, _settings: &Settings, _status: &Self::Status) -> Option<String> {
+        None
+    }
+
+    /// Name of the backend object, when it has to be unique among all resources of this kind.
+    fn global_name(&self, _settings: &Settings) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Look up an existing backend object and record it on the status, `false` when there is none.
+    ///
+    /// Nothing else may be touched yet, whether another resource manages the object is checked afterwards.
+    fn adopt(
+        &self,
+        _ctx: &Context,
+        _status: &mut Self::Status,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        async { Ok(false) }
+    }
+
+    /// Uid of the resource recorded on the backend object, `None` when it carries no marker.
+    ///
+    /// Only resources with an identity are marked, the others never leave a marker.
+    fn marker(&self, _ctx: &Context) -> impl Future<Output = Result<Option<String>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Record `uid` on the backend object, so cleanup knows console manages it.
+    fn mark(&self, _ctx: &Context, _uid: &str) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Read the backend object, `None` when it doesn't exist.
+    fn observe(&self, ctx: &Context)
+    -> impl Future<Output = Result<Option<Self::Observed>>> + Send;
+
+    fn create(
+        &self,
+        ctx: &Context,
+        status: &mut Self::Status,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Bring an existing backend object in line with the spec, returning what changed.
+    fn update(
+        &self,
+        ctx: &Context,
+        observed: Self::Observed,
+        status: &mut Self::Status,
+    ) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    fn delete(&self, ctx: &Context) -> impl Future<Output = Result<()>> + Send;
+
+    /// Leave the backend object in place, marked with `note` so it can be found later.
+    fn orphan(&self, ctx: &Context, note: &str) -> impl Future<Output = Result<()>> + Send;
+}
+
+fn label<K: Managed>() -> String {
+    K::kind(&()).to_lowercase()
+}
+
+fn current_status<K: Managed>(object: &K) -> K::Status {
+    object.status().cloned().unwrap_or_default()
+}
+
+async fn patch_status<K: Managed>(object: &K, ctx: &Context, status: K::Status) -> Result<()> {
+    let api: Api<K> = Api::namespaced(ctx.kube_client.clone(), &object.namespace().unwrap());
+    let new_status = Patch::Apply(json!({
+        "apiVersion": K::api_version(&()),
+        "kind": K::kind(&()),
+        "status": status,
+    }));
+    let ps = PatchParams::apply("cntrlr").force();
+    api.patch_status(&object.name_any(), &ps, &new_status)
+        .await
+        .map_err(Error::KubeError)?;
+    Ok(())
+}
+
+/// Record a failed reconcile on the latest status, leaving the other fields as the failed pass left them.
+///
+/// Conditions the failed pass wrote, like `ServerAvailable`, are kept. A status that already
+/// says the same is left alone, so a failure repeating itself doesn't trigger another reconcile.
+async fn patch_failure<K: Managed>(object: &K, ctx: &Context, error: LastError) -> Result<()> {
+    let api: Api<K> = Api::namespaced(ctx.kube_client.clone(), &object.namespace().unwrap());
+    let latest = api
+        .get_status(&object.name_any())
+        .await
+        .map_err(Error::KubeError)?;
+    let current = current_status(&latest);
+    let generation = object.meta().generation;
+
+    let mut reconcile = current.reconcile().clone();
+    let last_error = error.since(reconcile.last_error.as_ref());
+    reconcile.observed_generation = generation;
+    mark_failed(&mut reconcile.conditions, &last_error, generation);
+    reconcile.last_error = Some(last_error);
+    if reconcile.observed_generation == current.reconcile().observed_generation
+        && reconcile.conditions == current.reconcile().conditions
+        && reconcile.last_error == current.reconcile().last_error
+    {
+        return Ok(());
+    }
+
+    // Conflicts with a write since the read, instead of replacing its conditions.
+    let failure = Patch::Merge(json!({
+        "metadata": {
+            "resourceVersion": latest.resource_version(),
+        },
+        "status": {
+            "observedGeneration": reconcile.observed_generation,
+            "conditions": reconcile.conditions,
+            "lastError": reconcile.last_error,
+        }
+    }));
+    api.patch_status(&object.name_any(), &PatchParams::default(), &failure)
+        .await
+        .map_err(Error::KubeError)?;
+    Ok(())
+}
+
+pub(crate) async fn publish<K: Managed>(
+    object: &K,
+    ctx: &Context,
+    reason: &str,
+    action: &str,
+    note: String,
+) -> Result<()> {
+    ctx.recorder
+        .publish(
+            &Event {
+                type_: EventType::Normal,
+                reason: reason.into(),
+                note: Some(note),
+                action: action.into(),
+                secondary: None,
+            },
+            &object.object_ref(&()),
+        )
+        .await
+        .map_err(Error::KubeError)
+}
+
+async fn warn_event<K: Managed>(
+    object: &K,
+    ctx: &Context,
+    reason: &str,
+    action: &str,
+    note: String,
+) -> Result<()> {
+    ctx.recorder
+        .publish(
+            &Event {
+                type_: EventType::Warning,
+                reason: reason.into(),
+                note: Some(note),
+                action: action.into(),
+                secondary: None,
+            },
+            &object.object_ref(&()),
+        )
+        .await
+        .map_err(Error::KubeError)
+}
+
+/// Whether the backend object may be deleted, it has to carry this resource's marker.
+///
+/// A missing object is fine, deleting it is a no-op.
+async fn owns<K: Managed>(object: &K, ctx: &Context, status: &K::Status) -> Result<bool> {
+    if object.identity(&ctx.settings, status).is_none() || object.observe(ctx).await?.is_none() {
+        return Ok(true);
+    }
+    Ok(object.marker(ctx).await? == object.uid())
+}
+
+/// Record how far a long running create or update got on the Progressing condition.
+pub(crate) async fn report_progress<K: Managed>(
+    object: &K,
+    ctx: &Context,
+    status: &mut K::Status,
+    message: String,
+) -> Result<()> {
+    mark_progressing(
+        &mut status.reconcile_mut().conditions,
+        message,
+        object.meta().generation,
+    );
+    patch_status(object, ctx, status.clone()).await
+}
+
+/// Fail early when the backend can't be used, recording why on the status.
+async fn ensure_backend<K: Managed>(
+    object: &K,
+    ctx: &Context,
+    status: &mut K::Status,
+) -> Result<()> {
+    match object.backend() {
+        Backend::Postgresql(server) => {
+            let Some(pool) = ctx.postgresql_clients.get(&server) else {
+                return Err(Error::PostgresqlUnknownServer);
+            };
+            let health = pool.health();
+            let available = health.state != HealthState::Unhealthy;
+            let (reason, message) = if available {
+                (
+                    "ServerAvailable",
+                    format!("Postgresql server `{server}` is available"),
+                )
+            } else {
+                (
+                    "ServerUnavailable",
+                    format!(
+                        "Postgresql server `{server}` is unavailable: {}",
+                        health.last_error.unwrap_or_default()
+                    ),
+                )
+            };
+            set_condition(
+                &mut status.reconcile_mut().conditions,
+                "ServerAvailable",
+                available,
+                reason,
+                message,
+                object.meta().generation,
+            );
+            if !available {
+                patch_status(object, ctx, status.clone()).await?;
+                return Err(Error::PostgresqlServerUnavailable(server));
+            }
+            Ok(())
+        }
+        Backend::Garage => Ok(()),
+    }
+}
+
+/// Fail when the resource's namespace may not use its backend.
+async fn ensure_access<K: Managed>(object: &K, ctx: &Context) -> Result<()> {
+    let (policy, backend) = match object.backend() {
+        Backend::Postgresql(server) => (
+            ctx.settings
+                .postgresql
+                .get(&server)
+                .and_then(|settings| settings.access.as_ref()),
+            format!("postgresql server `{server}`"),
+        ),
+        Backend::Garage => (ctx.settings.s3.access.as_ref(), "s3".to_string()),
+    };
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+
+    let namespace = object.namespace().unwrap();
+    let labels = if policy.needs_labels(&namespace) {
+        Api::<Namespace>::all(ctx.kube_client.clone())
+            .get(&namespace)
+            .await
+            .map_err(Error::KubeError)?
+            .labels()
+            .clone()
+    } else {
+        BTreeMap::new()
+    };
+    if !policy.allows(&namespace, &labels) {
+        return Err(Error::AccessDenied(format!(
+            "namespace `{namespace}` may not use {backend}"
+        )));
+    }
+    Ok(())
+}
+
+/// Fail when an older resource of the same kind already claims the backend name.
+///
+/// Created resources keep their name, a new one asking for it is the one that loses.
+async fn check_collision<K: Managed>(object: &K, ctx: &Context, status: &K::Status) -> Result<()> {
+    let Some(global_name) = object.global_name(&ctx.settings)? else {
+        return Ok(());
+    };
+    if status.reconcile().created {
+        return Ok(());
+    }
+
+    let api = Api::<K>::all(ctx.kube_client.clone());
+    let precedes = |other: &K| {
+        other
+            .status()
+            .is_some_and(|other_status| other_status.reconcile().created)
+            || (other.creation_timestamp(), other.uid())
+                < (object.creation_timestamp(), object.uid())
+    };
+    let winner = api
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::KubeError)?
+        .into_iter()
+        .find(|other| {
+            other.uid() != object.uid()
+                && other.global_name(&ctx.settings).ok().flatten().as_ref() == Some(&global_name)
+                && precedes(other)
+        });
+
+    match winner {
+        Some(winner) => Err(Error::NameCollision(format!(
+            "{} `{global_name}` is already claimed by {}/{}",
+            label::<K>(),
+            winner.namespace().unwrap_or_default(),
+            winner.name_any()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Take over an existing backend object when the adopt annotation asks for it.
+///
+/// Refuses objects another resource already manages, so two resources never fight over one.
+async fn adopt<K: Managed>(object: &K, ctx: &Context, status: &mut K::Status) -> Result<bool> {
+    let requested = object
+        .annotations()
+        .get(ADOPT_ANNOTATION)
+        .is_some_and(|value| value == "true");
+    if status.reconcile().created || !requested || !object.adopt(ctx, status).await? {
+        return Ok(false);
+    }
+
+    let name = object.name_any();
+    let label = label::<K>();
+    let identity = object.identity(&ctx.settings, status);
+    let api = Api::<K>::all(ctx.kube_client.clone());
+    let owner = api
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::KubeError)?
+        .into_iter()
+        .find(|other| {
+            other.uid() != object.uid()
+                && other.status().is_some_and(|other_status| {
+                    other_status.reconcile().created
+                        && identity.is_some()
+                        && other.identity(&ctx.settings, other_status) == identity
+                })
+        });
+    if let Some(owner) = owner {
+        return Err(Error::AlreadyManaged(format!(
+            "{label} `{name}` is managed by {}/{}",
+            owner.namespace().unwrap_or_default(),
+            owner.name_any()
+        )));
+    }
+    // A resource that's gone by now, or lives in another cluster, may still hold the object.
+    if let Some(marker) = object.marker(ctx).await?
+        && Some(&marker) != object.uid().as_ref()
+    {
+        return Err(Error::AlreadyManaged(format!(
+            "{label} `{name}` carries the marker of resource {marker}"
+        )));
+    }
+
+    let reconcile = status.reconcile_mut();
+    reconcile.created = true;
+    reconcile.adopted = true;
+    reconcile.marked = false;
+    publish(
+        object,
+        ctx,
+        "AdoptionCompleted",
+        "Adopted",
+        format!("Adopted existing {label} for `{name}`"),
+    )
+    .await?;
+    Ok(true)
+}
+
+async fn apply<K: Managed>(object: &K, ctx: &Context) -> Result<Action> {
+    let name = object.name_any();
+    let label = label::<K>();
+    let generation = object.meta().generation;
+    let mut status = current_status(object);
+
+    if status.reconcile().observed_generation != generation {
+        mark_progressing(
+            &mut status.reconcile_mut().conditions,
+            format!("Reconciling {label} for `{name}`"),
+            generation,
+        );
+        patch_status(object, ctx, status.clone()).await?;
+    }
+
+    object.validate()?;
+    ensure_access(object, ctx).await?;
+    ensure_backend(object, ctx, &mut status).await?;
+    check_collision(object, ctx, &status).await?;
+
+    let mut observed = object.observe(ctx).await?;
+    // Objects found by a lookup, like Garage buckets by alias, can only be read once adopted.
+    if adopt(object, ctx, &mut status).await? && observed.is_none() {
+        observed = object.observe(ctx).await?;
+    }
+
+    if observed.is_some() && !status.reconcile().created {
+        // An earlier pass created and marked it, but couldn't record that on the status.
+        if object.uid().is_none() || object.marker(ctx).await? != object.uid() {
+            return Err(Error::AlreadyExists(format!("{label} `{name}`")));
+        }
+        let reconcile = status.reconcile_mut();
+        reconcile.created = true;
+        reconcile.marked = true;
+    }
+
+    match observed {
+        None => {
+            let (reason, note) = if status.reconcile().created {
+                (
+                    "RecreationRequested",
+                    format!("Recreating {label} for `{name}`, it is missing on the backend"),
+                )
+            } else {
+                (
+                    "CreationRequested",
+                    format!("Creating {label} for `{name}`"),
+                )
+            };
+            publish(object, ctx, reason, "Creating", note).await?;
+
+            // A recreated object starts without the marker, unless create already set it.
+            status.reconcile_mut().marked = false;
+            object.create(ctx, &mut status).await?;
+            // Recorded right away, so nothing failing from here on can make the new object look foreign.
+            status.reconcile_mut().created = true;
+            patch_status(object, ctx, status.clone()).await?;
+
+            publish(
+                object,
+                ctx,
+                "CreationCompleted",
+                "Created",
+                format!("Created {label} for `{name}`"),
+            )
+            .await?;
+        }
+        Some(observed) => {
+            let changes = object.update(ctx, observed, &mut status).await?;
+            if !changes.is_empty() {
+                publish(
+                    object,
+                    ctx,
+                    "UpdateCompleted",
+                    "Updated",
+                    format!("Updated {label} for `{name}`: {}", changes.join(", ")),
+                )
+                .await?;
+            }
+        }
+    }
+
+    // Objects created before markers existed are marked on their first reconcile since.
+    if !status.reconcile().marked && object.identity(&ctx.settings, &status).is_some() {
+        object.mark(ctx, &object.uid().unwrap_or_default()).await?;
+        status.reconcile_mut().marked = true;
+    }
+
+    let reconcile = status.reconcile_mut();
+    reconcile.created = true;
+    reconcile.observed_generation = generation;
+    reconcile.last_error = None;
+    mark_ready(
+        &mut reconcile.conditions,
+        format!("{} for `{name}` is ready", K::kind(&())),
+        generation,
+    );
+    // If no events were received, check back later anyway
+    let requeue_after = object.requeue_after(&status);
+    patch_status(object, ctx, status).await?;
+
+    Ok(Action::requeue(requeue_after))
+}
+
+async fn cleanup<K: Managed>(object: &K, ctx: &Context) -> Result<Action> {
+    let name = object.name_any();
+    let label = label::<K>();
+    let policy = object
+        .deletion_policy()
+        .unwrap_or(ctx.settings.deletion_policy);
+
+    publish(
+        object,
+        ctx,
+        "DeleteRequested",
+        "Deleting",
+        format!("Deleting {label} for `{name}` with deletion policy {policy:?}"),
+    )
+    .await?;
+
+    let mut status = current_status(object);
+    // Nothing was provisioned if creation never completed.
+    if status.reconcile().created {
+        ensure_backend(object, ctx, &mut status).await?;
+        match policy {
+            DeletionPolicy::Delete if !owns(object, ctx, &status).await? => {
+                warn_event(
+                    object,
+                    ctx,
+                    "DeleteRefused",
+                    "Deleting",
+                    format!(
+                        "Not deleting {label} for `{name}`, the backend object doesn't carry its ownership marker"
+                    ),
+                )
+                .await?;
+            }
+            DeletionPolicy::Delete => object.delete(ctx).await?,
+            DeletionPolicy::Retain => {
+                let note = format!(
+                    "orphaned by console.tjo.cloud, was {} {}/{name}",
+                    K::kind(&()),
+                    object.namespace().unwrap()
+                );
+                object.orphan(ctx, &note).await?;
+                publish(
+                    object,
+                    ctx,
+                    "DeleteRetained",
+                    "Retained",
+                    format!("Retained {label} for `{name}` on the backend"),
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(Action::await_change())
+}
+
+#[instrument(skip(ctx, object), fields(trace_id))]
+async fn reconcile<K: Managed>(object: Arc<K>, ctx: Arc<Context>) -> Result<Action> {
+    let oref = object.object_ref(&());
+
+    let trace_id = telemetry::get_trace_id();
+    if trace_id != opentelemetry::trace::TraceId::INVALID {
+        Span::current().record("trace_id", field::display(&trace_id));
+    }
+    let _timer = ctx.metrics.reconcile.count_and_measure(
+        oref.api_version.unwrap(),
+        oref.kind.unwrap(),
+        &trace_id,
+    );
+    ctx.diagnostics.write().await.last_event = Utc::now();
+    let ns = object.namespace().unwrap();
+    let api: Api<K> = Api::namespaced(ctx.kube_client.clone(), &ns);
+
+    info!(
+        "Reconciling {} \"{}\" in {}",
+        K::kind(&()),
+        object.name_any(),
+        ns
+    );
+    let key = format!("{ns}/{}", object.name_any());
+    let action = finalizer(&api, FINALIZER, object, |event| async {
+        match event {
+            Finalizer::Apply(object) => apply(object.as_ref(), &ctx).await,
+            Finalizer::Cleanup(object) => cleanup(object.as_ref(), &ctx).await,
+        }
+    })
+    .await
+    .map_err(|e| Error::FinalizerError(Box::new(e)))?;
+
+    ctx.backoff.reset(&key);
+    Ok(action)
+}
+
+fn error_policy<K: Managed>(object: Arc<K>, error: &Error, ctx: Arc<Context>) -> Action {
+    error!("reconcile failed: {:?}", error);
+    let oref = object.object_ref(&());
+
+    ctx.metrics.reconcile.set_failure(
+        oref.api_version.unwrap(),
+        oref.kind.unwrap(),
+        object.name_any(),
+        error,
+    );
+
+    // Error policies are synchronous, so the status is written in the background.
+    let (failed, ctx_, last_error) = (object.clone(), ctx.clone(), LastError::new(error, None));
+    tokio::spawn(async move {
+        if let Err(err) = patch_failure(failed.as_ref(), &ctx_, last_error).await {
+            warn!("failed to record reconcile failure: {err:?}");
+        }
+    });
+
+    match error.class() {
+        ErrorClass::Transient => {
+            let key = format!("{}/{}", object.namespace().unwrap(), object.name_any());
+            Action::requeue(ctx.backoff.next(&key))
+        }
+        ErrorClass::Permanent => Action::await_change(),
+        // Namespaces aren't watched, so a change to their labels is only noticed on the next check.
+        ErrorClass::External => Action::requeue(Duration::from_secs(30 * 60)),
+    }
+}
+
+/// Initialize the controller for `K` (given the crd is installed)
+pub async fn run<K: Managed>(context: Arc<Context>, kube_client: KubeClient) -> Result<(), Error> {
+    let api = Api::<K>::all(kube_client.clone());
+    match api.list(&ListParams::default().limit(1)).await {
+        Err(err) => return Err(Error::MissingCrds(err)),
+        Ok(_) => info!("CRDs for {} are installed!", K::kind(&())),
+    };
+
+    info!("Starting controller");
+
+    Controller::new(api, Config::default().any_semantic())
+        .shutdown_on_signal()
+        .run(reconcile::<K>, error_policy::<K>, context)
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()))
+        .await;
+
+    Ok(())
+}
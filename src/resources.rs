@@ -1,2 +1,29 @@
+use crate::{Error, Result};
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{Api, DeleteParams, PostParams};
+
 pub mod postgresql;
 pub mod s3;
+
+/// Delete the secret `name`, one that's already gone is fine.
+pub async fn delete_secret(secrets: &Api<Secret>, name: &str) -> Result<()> {
+    match secrets.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(Error::KubeError(err)),
+    }
+}
+
+/// Create `secret`, replacing an existing one of the same name.
+///
+/// Generated secrets are immutable, so new content always means a new object. Only for
+/// secrets nothing uses yet, logins are published as new versions instead.
+pub async fn replace_secret(secrets: &Api<Secret>, secret: &Secret) -> Result<()> {
+    delete_secret(secrets, secret.metadata.name.as_deref().unwrap_or_default()).await?;
+    secrets
+        .create(&PostParams::default(), secret)
+        .await
+        .map_err(Error::KubeError)?;
+
+    Ok(())
+}
@@ -1,48 +1,165 @@
-use crate::{Error, Result};
+use crate::{Error, Result, settings::Postgresql};
 use native_tls::TlsConnector;
 use postgres_native_tls::MakeTlsConnector;
+use tokio::time::Duration;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::error::SqlState;
 use tracing::*;
 
+pub mod backup;
+pub mod backup_schedule;
 pub mod database;
+mod dump;
+pub mod grant;
+mod login;
+mod pool;
+pub mod restore;
+pub mod schema;
+pub mod sql;
+mod statements;
 pub mod user;
 
+pub use pool::{DatabasePools, Health, HealthState, Pool, PooledClient};
 pub use tokio_postgres::Client;
 
-pub async fn connect(
-    name: String,
-    host: String,
-    database: String,
-    user: String,
-    password: String,
-    sslmode: String,
-    ssl_accept_invalid_cert: bool,
-) -> Result<Client, Error> {
+pub async fn connect(name: &str, settings: &Postgresql) -> Result<Client, Error> {
     let connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(ssl_accept_invalid_cert)
+        .danger_accept_invalid_certs(settings.ssl_accept_invalid_cert)
         .build()?;
     let connector = MakeTlsConnector::new(connector);
 
+    let sslmode = match settings.sslmode.as_str() {
+        "disable" => SslMode::Disable,
+        "prefer" => SslMode::Prefer,
+        "require" => SslMode::Require,
+        _ => return Err(Error::PostgresqlIllegalSslMode(settings.sslmode.clone())),
+    };
+    let connect_timeout = Duration::from_secs(settings.connect_timeout_seconds);
+
+    let Postgresql {
+        host,
+        port,
+        user,
+        database,
+        ssl_accept_invalid_cert,
+        ..
+    } = settings;
+
     info!(
-        "Connecting to Postgresql name={name} host={host} user={user} database={database} sslmode={sslmode} ssl_accept_invalid_cert={ssl_accept_invalid_cert}"
+        "Connecting to Postgresql name={name} host={host} port={port} user={user} database={database} sslmode={0} ssl_accept_invalid_cert={ssl_accept_invalid_cert}",
+        settings.sslmode
     );
 
-    let (client, connection) = tokio_postgres::connect(
-        &format!(
-            "application_name=console-tjo-cloud host={host} user={user} password={password} dbname={database} sslmode={sslmode}"
-        ),
-        connector,
-    )
-    .await?;
+    let mut config = tokio_postgres::Config::new();
+    config
+        .application_name(APPLICATION_NAME)
+        .host(host)
+        .port(*port)
+        .user(user)
+        .password(&settings.password)
+        .dbname(database)
+        .ssl_mode(sslmode)
+        .connect_timeout(connect_timeout)
+        // Server side guard, in case the client side timeout can't cancel the statement.
+        .options(format!(
+            "-c statement_timeout={}",
+            settings.statement_timeout_seconds * 1000
+        ));
+
+    let (client, connection) = tokio::time::timeout(connect_timeout, config.connect(connector))
+        .await
+        .map_err(|_| Error::PostgresqlTimeout)??;
 
     info!(
-        "Connected to Postgresql name={name} host={host} user={user} database={database} sslmode={sslmode} ssl_accept_invalid_cert={ssl_accept_invalid_cert}"
+        "Connected to Postgresql name={name} host={host} port={port} user={user} database={database} sslmode={0} ssl_accept_invalid_cert={ssl_accept_invalid_cert}",
+        settings.sslmode
     );
 
+    let name = name.to_string();
     tokio::spawn(async move {
         if let Err(e) = connection.await {
-            panic!("connection error: {}", e);
+            warn!("Postgresql connection closed name={name}: {e}");
         }
     });
 
     Ok(client)
 }
+
+/// The database, schema or role a statement refers to doesn't exist, so there's nothing left to clean up.
+pub fn is_missing(err: &Error) -> bool {
+    match err {
+        Error::PostgresqlClientError(err) => err.code().is_some_and(|code| {
+            *code == SqlState::INVALID_CATALOG_NAME
+                || *code == SqlState::INVALID_SCHEMA_NAME
+                || *code == SqlState::UNDEFINED_OBJECT
+        }),
+        _ => false,
+    }
+}
+
+/// Application name of every connection console opens, so its own sessions can be told apart.
+pub const APPLICATION_NAME: &str = "console-tjo-cloud";
+
+/// Start of the comment that marks databases and roles with the uid of the resource managing them.
+const MARKER_PREFIX: &str = "managed by console.tjo.cloud, uid ";
+
+/// Comment recording that the resource with `uid` manages the object.
+pub fn ownership_comment(uid: &str) -> String {
+    format!("{MARKER_PREFIX}{uid}")
+}
+
+/// Uid recorded in a comment, `None` for comments console didn't write.
+///
+/// Only the first line counts, database comments continue with the restores done in them.
+pub fn ownership_marker(comment: &str) -> Option<&str> {
+    comment.lines().next()?.strip_prefix(MARKER_PREFIX)
+}
+
+/// Start of the database comment lines recording a completed restore.
+const RESTORED_PREFIX: &str = "restored by console.tjo.cloud, uid ";
+
+/// `comment` followed by a line recording that the restore with `uid` completed.
+pub fn restored_comment(comment: &str, uid: &str) -> String {
+    if comment.is_empty() {
+        return format!("{RESTORED_PREFIX}{uid}");
+    }
+    format!("{comment}\n{RESTORED_PREFIX}{uid}")
+}
+
+/// Whether `comment` records that the restore with `uid` completed.
+pub fn restored(comment: &str, uid: &str) -> bool {
+    comment
+        .lines()
+        .any(|line| line.strip_prefix(RESTORED_PREFIX) == Some(uid))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ownership_comment_round_trips() {
+        let comment = ownership_comment("0b7f4a8e-7a4c-4a5e-9d0e-2f1c3b4a5d6e");
+        assert_eq!(
+            ownership_marker(&comment),
+            Some("0b7f4a8e-7a4c-4a5e-9d0e-2f1c3b4a5d6e")
+        );
+        assert_eq!(ownership_marker("hand made"), None);
+        assert_eq!(
+            ownership_marker("orphaned by console.tjo.cloud, was Database default/example"),
+            None
+        );
+    }
+
+    #[test]
+    fn restores_are_recorded_after_the_ownership_marker() {
+        let comment = restored_comment(&ownership_comment("db"), "first");
+        let comment = restored_comment(&comment, "second");
+        assert_eq!(ownership_marker(&comment), Some("db"));
+        assert!(restored(&comment, "first"));
+        assert!(restored(&comment, "second"));
+        assert!(!restored(&comment, "db"));
+        assert!(!restored(&comment, "fir"));
+        assert!(restored(&restored_comment("", "only"), "only"));
+    }
+}
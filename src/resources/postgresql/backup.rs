@@ -0,0 +1,220 @@
+use crate::{
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::{
+        postgresql::{
+            database::{Database, DatabaseRef},
+            dump::dump,
+        },
+        s3::bucket::{Bucket, BucketRef},
+    },
+};
+use chrono::Utc;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::{
+    CustomResource,
+    api::{Api, ResourceExt},
+    core::object::HasSpec,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+/// Logical backup of a Database, uploaded as an object into a Bucket
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(
+    kind = "Backup",
+    group = "postgresql.tjo.cloud",
+    version = "v1",
+    namespaced,
+    shortname = "bak",
+    status = "BackupStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Size", "type":"integer", "jsonPath":".status.sizeBytes"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupSpec {
+    pub database_ref: DatabaseRef,
+    pub bucket_ref: BucketRef,
+    /// What happens to the backup object when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_policy: Option<DeletionPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Bucket the dump was uploaded to.
+    #[serde(default)]
+    pub bucket: String,
+    /// Key of the uploaded dump.
+    #[serde(default)]
+    pub object_key: String,
+    #[serde(default)]
+    pub size_bytes: u64,
+    #[serde(default)]
+    pub duration_seconds: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<Time>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<Time>,
+}
+
+impl ManagedStatus for BackupStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+impl Backup {
+    fn status_or_default(&self) -> BackupStatus {
+        self.status.clone().unwrap_or_default()
+    }
+
+    async fn database(&self, ctx: &Context) -> Result<Database> {
+        let databases: Api<Database> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let database = databases
+            .get(&self.spec().database_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if !database
+            .status
+            .as_ref()
+            .is_some_and(|s| s.reconcile.created)
+        {
+            return Err(Error::NotReady(format!(
+                "database `{}`",
+                database.name_any()
+            )));
+        }
+        Ok(database)
+    }
+
+    async fn bucket(&self, ctx: &Context) -> Result<Bucket> {
+        let buckets: Api<Bucket> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let bucket = buckets
+            .get(&self.spec().bucket_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if bucket.get_id().is_empty() {
+            return Err(Error::NotReady(format!("bucket `{}`", bucket.name_any())));
+        }
+        Ok(bucket)
+    }
+}
+
+impl Managed for Backup {
+    type Observed = ();
+
+    /// The backup object lives in Garage, the database is only read while it's taken.
+    fn backend(&self) -> Backend {
+        Backend::Garage
+    }
+
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletion_policy
+    }
+
+    async fn observe(&self, _ctx: &Context) -> Result<Option<()>> {
+        // A backup is taken once, what was uploaded is recorded on the status.
+        if self.status_or_default().object_key.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(()))
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut BackupStatus) -> Result<()> {
+        let s3_client = ctx.s3_client.as_ref().ok_or(Error::S3NotConfigured)?;
+        let database = self.database(ctx).await?;
+        let bucket = self.bucket(ctx).await?;
+        let database_name = database.backend_name(&ctx.settings)?;
+        let bucket_name = bucket.backend_name(&ctx.settings)?;
+        let pool = ctx
+            .postgresql_databases
+            .get(&database.spec.server, &database_name)?;
+
+        let started_at = Utc::now();
+        let object_key = format!(
+            "{}/{}/{}-{}.sql",
+            self.namespace().unwrap(),
+            database_name,
+            started_at.format("%Y%m%dT%H%M%SZ"),
+            self.name_any()
+        );
+
+        let mut client = pool.session().await?;
+        // Large tables take longer to copy than any admin statement is allowed to.
+        client.batch_execute("SET statement_timeout = 0").await?;
+
+        let upload = async {
+            let mut upload = s3_client.upload(&bucket_name, &object_key).await?;
+            if let Err(err) = dump(&mut client, &mut upload).await {
+                if let Err(abort) = upload.abort().await {
+                    warn!("failed to abort upload of {object_key}: {abort:?}");
+                }
+                return Err(err);
+            }
+            Ok(upload.complete().await?)
+        };
+        let size = s3_client
+            .with_bucket(&ctx.garage_client, &bucket.get_id(), upload)
+            .await?;
+        let completed_at = Utc::now();
+
+        status.bucket = bucket_name;
+        status.object_key = object_key;
+        status.size_bytes = size;
+        status.duration_seconds = (completed_at - started_at).as_seconds_f64();
+        status.started_at = Some(Time(started_at));
+        status.completed_at = Some(Time(completed_at));
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        _ctx: &Context,
+        _observed: (),
+        _status: &mut BackupStatus,
+    ) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        let s3_client = ctx.s3_client.as_ref().ok_or(Error::S3NotConfigured)?;
+        let status = self.status_or_default();
+        // Without its bucket the dump is gone already.
+        let Some(bucket) = ctx
+            .garage_client
+            .get_bucket_by_alias(status.bucket.clone())
+            .await?
+        else {
+            return Ok(());
+        };
+        s3_client
+            .with_bucket(
+                &ctx.garage_client,
+                &bucket.id,
+                s3_client.delete_object(&status.bucket, &status.object_key),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Garage can't tag objects, so the dump is simply left in its bucket.
+    async fn orphan(&self, _ctx: &Context, _note: &str) -> Result<()> {
+        Ok(())
+    }
+}
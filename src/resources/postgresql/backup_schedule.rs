@@ -0,0 +1,304 @@
+use crate::{
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::{
+        postgresql::{
+            backup::{Backup, BackupSpec},
+            database::DatabaseRef,
+        },
+        s3::bucket::BucketRef,
+    },
+};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::{
+    CustomResource,
+    api::{Api, DeleteParams, ListParams, PostParams, ResourceExt},
+    core::object::HasSpec,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::time::Duration;
+
+/// Label linking a Backup to the schedule that created it.
+pub const SCHEDULE_LABEL: &str = "postgresql.tjo.cloud/backup-schedule";
+
+/// Recurring Backups of a Database, with old ones pruned
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(
+    kind = "BackupSchedule",
+    group = "postgresql.tjo.cloud",
+    version = "v1",
+    namespaced,
+    shortname = "baksched",
+    status = "BackupScheduleStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Schedule", "type":"string", "jsonPath":".spec.schedule"}"#,
+    printcolumn = r#"{"name":"Last Success", "type":"date", "jsonPath":".status.lastSuccessfulTime"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupScheduleSpec {
+    pub database_ref: DatabaseRef,
+    pub bucket_ref: BucketRef,
+    /// Cron expression in UTC, with five fields or six when it starts with seconds.
+    pub schedule: String,
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+/// Successful backups beyond either limit are deleted, together with their objects.
+///
+/// The newest successful backup is always kept, however old it is.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Retention {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupScheduleStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Scheduled time of the last Backup that was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_schedule_time: Option<Time>,
+    /// Completion time of the newest successful Backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_successful_time: Option<Time>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_schedule_time: Option<Time>,
+}
+
+impl ManagedStatus for BackupScheduleStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+/// Parse a cron expression, classic five field ones run at second zero.
+fn parse_schedule(expression: &str) -> Result<Schedule> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {expression}")
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|err| Error::IllegalSchedule(err.to_string()))
+}
+
+/// Latest time the schedule fired after `since`, up to and including `now`.
+fn last_due(
+    schedule: &Schedule,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&since)
+        .take_while(|time| *time <= now)
+        .last()
+}
+
+fn completed_at(backup: &Backup) -> Option<DateTime<Utc>> {
+    backup
+        .status
+        .as_ref()
+        .and_then(|s| s.completed_at.as_ref())
+        .map(|Time(time)| *time)
+}
+
+impl BackupSchedule {
+    /// Create a Backup when the schedule is due and prune the ones past retention.
+    async fn run(&self, ctx: &Context, status: &mut BackupScheduleStatus) -> Result<Vec<String>> {
+        let schedule = parse_schedule(&self.spec().schedule)?;
+        let backups: Api<Backup> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let now = Utc::now();
+        let mut changes = Vec::new();
+
+        let since = status
+            .last_schedule_time
+            .as_ref()
+            .map(|Time(time)| *time)
+            .or_else(|| self.creation_timestamp().map(|Time(time)| time))
+            .unwrap_or(now);
+        // Missed runs are collapsed into one, like a CronJob does.
+        if let Some(due) = last_due(&schedule, since, now) {
+            let name = format!("{}-{}", self.name_any(), due.format("%Y%m%d%H%M%S"));
+            let mut backup = Backup::new(
+                &name,
+                BackupSpec {
+                    database_ref: self.spec().database_ref.clone(),
+                    bucket_ref: self.spec().bucket_ref.clone(),
+                    // Pruning has to free the space, whatever the controller default is.
+                    deletion_policy: Some(DeletionPolicy::Delete),
+                },
+            );
+            backup
+                .labels_mut()
+                .insert(SCHEDULE_LABEL.to_string(), self.name_any());
+
+            match backups.create(&PostParams::default(), &backup).await {
+                Ok(_) => changes.push(format!("created backup {name}")),
+                Err(kube::Error::Api(err)) if err.code == 409 => {}
+                Err(err) => return Err(Error::KubeError(err)),
+            }
+            status.last_schedule_time = Some(Time(due));
+        }
+        status.next_schedule_time = schedule.after(&now).next().map(Time);
+
+        let mut taken = backups
+            .list(&ListParams::default().labels(&format!("{SCHEDULE_LABEL}={}", self.name_any())))
+            .await
+            .map_err(Error::KubeError)?
+            .items;
+        taken.sort_by_key(|backup| std::cmp::Reverse(backup.creation_timestamp()));
+
+        let newest_success = taken.iter().find_map(completed_at);
+        if let Some(time) = newest_success {
+            status.last_successful_time = Some(Time(time));
+            ctx.metrics.backup.set_last_success(
+                self.namespace().unwrap(),
+                self.name_any(),
+                self.spec().database_ref.name.clone(),
+                time.timestamp(),
+            );
+        }
+
+        let retention = &self.spec().retention;
+        let mut kept = 0;
+        for backup in taken {
+            let expired = match completed_at(&backup) {
+                Some(time) => {
+                    kept += 1;
+                    // When newer runs keep failing, the last good backup must not age out.
+                    kept > 1
+                        && (retention.keep_last.is_some_and(|keep| kept > keep)
+                            || retention.max_age_days.is_some_and(|days| {
+                                now - time > chrono::Duration::days(days.into())
+                            }))
+                }
+                // Failed or unfinished backups only go once a newer one succeeded.
+                None => {
+                    newest_success.is_some_and(|newest| {
+                        backup
+                            .creation_timestamp()
+                            .is_some_and(|Time(created)| created < newest)
+                    }) && backup
+                        .status
+                        .as_ref()
+                        .is_some_and(|s| s.reconcile.last_error.is_some())
+                }
+            };
+            if expired && backup.metadata.deletion_timestamp.is_none() {
+                backups
+                    .delete(&backup.name_any(), &DeleteParams::default())
+                    .await
+                    .map_err(Error::KubeError)?;
+                changes.push(format!("pruned backup {}", backup.name_any()));
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+impl Managed for BackupSchedule {
+    type Observed = ();
+
+    /// Backups are stored in Garage, the schedule itself has no backend object.
+    fn backend(&self) -> Backend {
+        Backend::Garage
+    }
+
+    /// Backups already taken stay around when their schedule is deleted.
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        Some(DeletionPolicy::Delete)
+    }
+
+    fn validate(&self) -> Result<()> {
+        parse_schedule(&self.spec().schedule).map(|_| ())
+    }
+
+    fn requeue_after(&self, status: &BackupScheduleStatus) -> Duration {
+        let resync = Duration::from_secs(5 * 60);
+        status
+            .next_schedule_time
+            .as_ref()
+            .and_then(|Time(next)| (*next - Utc::now()).to_std().ok())
+            .map_or(resync, |until| until.min(resync))
+    }
+
+    async fn observe(&self, _ctx: &Context) -> Result<Option<()>> {
+        let created = self.status.as_ref().is_some_and(|s| s.reconcile.created);
+        Ok(created.then_some(()))
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut BackupScheduleStatus) -> Result<()> {
+        self.run(ctx, status).await?;
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        ctx: &Context,
+        _observed: (),
+        status: &mut BackupScheduleStatus,
+    ) -> Result<Vec<String>> {
+        self.run(ctx, status).await
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        ctx.metrics.backup.remove_last_success(
+            self.namespace().unwrap(),
+            self.name_any(),
+            self.spec().database_ref.name.clone(),
+        );
+        Ok(())
+    }
+
+    async fn orphan(&self, _ctx: &Context, _note: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_schedule_accepts_five_and_six_fields() {
+        assert!(parse_schedule("0 3 * * *").is_ok());
+        assert!(parse_schedule("30 0 3 * * *").is_ok());
+        assert!(matches!(
+            parse_schedule("every night"),
+            Err(Error::IllegalSchedule(_))
+        ));
+    }
+
+    #[test]
+    fn last_due_collapses_missed_runs() {
+        let schedule = parse_schedule("0 3 * * *").unwrap();
+        let since = Utc.with_ymd_and_hms(2024, 1, 1, 3, 0, 0).unwrap();
+
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(last_due(&schedule, since, now), None);
+
+        let now = Utc.with_ymd_and_hms(2024, 1, 4, 12, 0, 0).unwrap();
+        assert_eq!(
+            last_due(&schedule, since, now),
+            Some(Utc.with_ymd_and_hms(2024, 1, 4, 3, 0, 0).unwrap())
+        );
+    }
+}
@@ -1,28 +1,25 @@
 use crate::{
-    Context, Error, FINALIZER, Result,
-    resources::postgresql::user::{User, UserRef},
-    telemetry,
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::postgresql::{
+        APPLICATION_NAME,
+        dump::USER_SCHEMA,
+        grant::{AppliedGrant, grant_statements},
+        is_missing,
+        login::{LoginSecret, generate_password},
+        ownership_comment, ownership_marker, sql,
+        user::{User, UserRef},
+    },
+    settings::Settings,
 };
-use chrono::Utc;
-use futures::StreamExt;
 use kube::{
     CustomResource, Resource,
-    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
-    client::Client as KubeClient,
+    api::{Api, ListParams, ResourceExt},
     core::object::HasSpec,
-    runtime::{
-        controller::{Action, Controller},
-        events::{Event, EventType},
-        finalizer::{Event as Finalizer, finalizer},
-        watcher::Config,
-    },
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::sync::Arc;
-use tokio::time::Duration;
-use tracing::*;
+use std::collections::BTreeMap;
 
 /// Database on the postgresql.tjo.cloud database platform
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
@@ -33,14 +30,44 @@ use tracing::*;
     version = "v1",
     namespaced,
     shortname = "dat",
-    status = "DatabaseStatus"
+    status = "DatabaseStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
+#[allow(non_snake_case)]
 pub struct DatabaseSpec {
-    #[schemars(length(min = 3, max = 63), pattern(r"[a-z0-9._]+"))]
+    #[schemars(length(min = 3, max = 63), pattern(r"^[a-z0-9._]+$"))]
     pub name: String,
     pub server: String,
     pub connectionLimit: i32,
     pub ownerRef: UserRef,
+    /// Extensions created in the database, each has to be allowed for the server.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Login role that can only read, created and dropped together with the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readOnlyUser: Option<ReadOnlyUser>,
+    /// What happens to the database when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletionPolicy: Option<DeletionPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadOnlyUser {
+    /// Name of the role, defaults to the database name with a `_ro` suffix.
+    ///
+    /// It can't be the role of a User, and an existing role is only used when this Database created it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(length(min = 3, max = 63), pattern(r"^[a-z0-9._]+$"))]
+    pub name: Option<String>,
+    /// Name of the secret that will be created and contain the generated password.
+    ///
+    /// A changed login is published as a new secret named after it,
+    /// `status.readOnlyPasswordSecretName` holds the one in use.
+    pub password_secret_name: String,
+    pub connection_limit: i32,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
@@ -49,49 +76,64 @@ pub struct DatabaseRef {
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct DatabaseStatus {
-    pub created: bool,
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Owner applied on the last successful reconcile.
+    #[serde(default)]
+    pub owner: String,
+    /// Connection limit applied on the last successful reconcile.
+    #[serde(default)]
+    pub connection_limit: i32,
+    /// Extensions created on the last successful reconcile, only these are ever dropped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Read-only role created on the last successful reconcile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only_user: Option<String>,
+    /// Secret holding the read-only role's login now.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only_password_secret_name: Option<String>,
+    /// Name the database was created under, later changes of the naming policy don't rename it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_name: Option<String>,
 }
 
-impl Database {
-    fn was_created(&self) -> bool {
-        self.status.as_ref().map(|s| s.created).unwrap_or(false)
-    }
-
-    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
-        let oref = self.object_ref(&());
-        let namespace = self.namespace().unwrap();
-        let name = self.name_any();
-        let databases: Api<Database> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-
-        // If was already created, do nothing.
-        if self.was_created() {
-            return Ok(Action::requeue(Duration::from_secs(5 * 60)));
-        }
-
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationRequested".into(),
-                    note: Some(format!("Creating database for `{name}`")),
-                    action: "Creating".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
+impl ManagedStatus for DatabaseStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
 
-        if name == "illegal" {
-            return Err(Error::PostgresqlIllegalDatabase);
-        }
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+/// Live state of a database, as read from `pg_database`.
+pub struct ObservedDatabase {
+    pub connection_limit: i32,
+    pub owner: String,
+}
 
-        if !ctx.postgresql_clients.contains_key(&self.spec().server) {
-            return Err(Error::PostgresqlUnknownServer);
+impl Database {
+    /// Name of the database on the server, the naming policy only applies until it's recorded on the status.
+    pub fn backend_name(&self, settings: &Settings) -> Result<String> {
+        match self
+            .status
+            .as_ref()
+            .and_then(|status| status.backend_name.clone())
+        {
+            Some(name) => Ok(name),
+            None => settings
+                .naming
+                .apply(&self.namespace().unwrap(), &self.spec().name),
         }
+    }
 
+    /// Owning user, which has to live on the same server.
+    async fn owner(&self, ctx: &Context) -> Result<User> {
+        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
         let user: User = users
             .get(&self.spec().ownerRef.name)
             .await
@@ -101,136 +143,510 @@ impl Database {
             return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
         }
 
-        ctx.postgresql_clients[&self.spec().server]
-            .execute(
-                &format!(
-                    "CREATE DATABASE {} WITH OWNER '{}' CONNECTION LIMIT {}",
-                    self.spec().name.clone(),
-                    user.spec.name,
-                    self.spec().connectionLimit
-                ),
-                &[],
-            )
+        Ok(user)
+    }
+
+    /// Create the listed extensions and drop the ones that were removed from the list.
+    ///
+    /// Extensions live inside the database, so this goes through its own pool
+    /// rather than the server's admin pool.
+    async fn sync_extensions(
+        &self,
+        ctx: &Context,
+        status: &mut DatabaseStatus,
+    ) -> Result<Vec<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let wanted = &self.spec().extensions;
+        let removed: Vec<&String> = status
+            .extensions
+            .iter()
+            .filter(|extension| !wanted.contains(extension))
+            .collect();
+        if wanted.is_empty() && removed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let settings = ctx
+            .settings
+            .postgresql()
+            .get(&self.spec().server)
+            .ok_or(Error::PostgresqlUnknownServer)?;
+        if let Some(extension) = wanted
+            .iter()
+            .find(|extension| !settings.extensions.contains(extension))
+        {
+            return Err(Error::PostgresqlExtensionNotAllowed(extension.clone()));
+        }
+
+        let pool = ctx.postgresql_databases.get(&self.spec().server, &name)?;
+        let installed: Vec<String> = pool
+            .query("SELECT extname FROM pg_extension", &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let mut changes = Vec::new();
+
+        for extension in wanted {
+            if !installed.contains(extension) {
+                pool.execute(&sql::create_extension(extension)?, &[])
+                    .await?;
+                changes.push(format!("created extension {extension}"));
+            }
+        }
+        for extension in removed {
+            if installed.contains(extension) {
+                pool.execute(&sql::drop_extension(extension)?, &[]).await?;
+                changes.push(format!("dropped extension {extension}"));
+            }
+        }
+
+        status.extensions = wanted.clone();
+        Ok(changes)
+    }
+
+    /// Role of the read-only user, a name given on the spec goes through the naming policy as well.
+    fn read_only_user_name(&self, settings: &Settings) -> Result<Option<String>> {
+        let Some(user) = self.spec().readOnlyUser.as_ref() else {
+            return Ok(None);
+        };
+        match &user.name {
+            Some(name) => settings.naming.apply(&self.namespace().unwrap(), name),
+            None => Ok(format!("{}_ro", self.backend_name(settings)?)),
+        }
+        .map(Some)
+    }
+
+    /// Drop the database once console's own sessions on it are gone.
+    ///
+    /// Pooled connections may still be closing after the pool is forgotten, so they are ended
+    /// explicitly. Tenant sessions are left alone and make the drop fail until they disconnect.
+    async fn drop_database(&self, ctx: &Context, name: &str) -> Result<()> {
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        ctx.postgresql_databases.close(&self.spec().server, name);
+        client
+            .execute(&sql::terminate_backends(name, APPLICATION_NAME)?, &[])
             .await?;
+        client.execute(&sql::drop_database(name)?, &[]).await?;
+        Ok(())
+    }
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationCompleted".into(),
-                    note: Some(format!("Created database for `{name}`")),
-                    action: "Created".into(),
-                    secondary: None,
-                },
-                &oref,
+    /// Whether the read-only role may be dropped, it has to carry this resource's marker.
+    ///
+    /// A missing role is fine, dropping it is a no-op.
+    async fn owns_read_only_user(&self, ctx: &Context, role: &str) -> Result<bool> {
+        let row = ctx.postgresql_clients[&self.spec().server]
+            .query_opt(
+                "SELECT shobj_description(oid, 'pg_authid') FROM pg_roles WHERE rolname = $1",
+                &[&role],
             )
+            .await?;
+        Ok(match row {
+            None => true,
+            Some(row) => {
+                let comment: Option<String> = row.get(0);
+                comment.as_deref().and_then(ownership_marker) == self.uid().as_deref()
+            }
+        })
+    }
+
+    /// Connection limit of the read-only role when it exists, failing for roles this resource doesn't own.
+    ///
+    /// A User's role is refused even before it exists.
+    async fn claim_read_only_user(
+        &self,
+        ctx: &Context,
+        role: &str,
+        previous: Option<&String>,
+    ) -> Result<Option<i32>> {
+        let users = Api::<User>::all(ctx.kube_client.clone())
+            .list(&ListParams::default())
             .await
             .map_err(Error::KubeError)?;
+        if let Some(user) = users.into_iter().find(|user| {
+            user.spec.server == self.spec().server
+                && user
+                    .backend_name(&ctx.settings)
+                    .is_ok_and(|name| name == role)
+        }) {
+            return Err(Error::AlreadyExists(format!(
+                "role `{role}` of user {}/{}",
+                user.namespace().unwrap_or_default(),
+                user.name_any()
+            )));
+        }
 
-        let new_status = Patch::Apply(json!({
-            "apiVersion": "postgresql.tjo.cloud/v1",
-            "kind": "Database",
-            "status": DatabaseStatus {
-                created : true,
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        let Some(row) = client
+            .query_opt(
+                "SELECT rolconnlimit, shobj_description(oid, 'pg_authid') FROM pg_roles WHERE rolname = $1",
+                &[&role],
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+        let uid = self.uid().unwrap_or_default();
+        let comment: Option<String> = row.get(1);
+        match comment.as_deref().and_then(ownership_marker) {
+            Some(marker) if marker == uid => {}
+            // Read-only roles created before they were marked get it once.
+            None if previous.is_some_and(|previous| previous == role) => {
+                client
+                    .execute(&sql::comment_on_role(role, &ownership_comment(&uid))?, &[])
+                    .await?;
             }
-        }));
-        let ps = PatchParams::apply("cntrlr").force();
-        databases
-            .patch_status(&name, &ps, &new_status)
-            .await
-            .map_err(Error::KubeError)?;
+            _ => return Err(Error::AlreadyExists(format!("role `{role}`"))),
+        }
+        Ok(Some(row.get(0)))
+    }
 
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
+    /// Drop a read-only role this resource owns, along with its privileges inside the database.
+    async fn drop_read_only_user(&self, ctx: &Context, role: &str) -> Result<()> {
+        if !self.owns_read_only_user(ctx, role).await? {
+            return Ok(());
+        }
+        let name = self.backend_name(&ctx.settings)?;
+        let dropped = ctx
+            .postgresql_databases
+            .get(&self.spec().server, &name)?
+            .execute(&sql::drop_owned_by(role)?, &[])
+            .await;
+        // A role that's already gone has nothing left to drop.
+        if let Err(err) = dropped
+            && !is_missing(&err)
+        {
+            return Err(err);
+        }
+        ctx.postgresql_clients[&self.spec().server]
+            .execute(&sql::drop_user_if_exists(role)?, &[])
+            .await?;
+        Ok(())
     }
 
-    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
-        let oref = self.object_ref(&());
-        let name = self.name_any();
+    /// Create the read-only role with its secret and give it SELECT on everything the owner has.
+    ///
+    /// Privileges are granted again on every reconcile, so schemas created since are covered too.
+    async fn sync_read_only_user(
+        &self,
+        ctx: &Context,
+        owner: &str,
+        status: &mut DatabaseStatus,
+    ) -> Result<Vec<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let wanted = self.read_only_user_name(&ctx.settings)?;
+        let previous = status.read_only_user.clone();
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        let uid = self.uid().unwrap_or_default();
+        let mut changes = Vec::new();
+
+        // Checked before the previous role goes, so a name that can't be used changes nothing.
+        let existing = match &wanted {
+            Some(role) => {
+                self.claim_read_only_user(ctx, role, previous.as_ref())
+                    .await?
+            }
+            None => None,
+        };
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "DeleteRequested".into(),
-                    note: Some(format!("Dropping database for `{}`", name)),
-                    action: "Deleting".into(),
-                    secondary: None,
-                },
-                &oref,
+        if let Some(previous) = previous
+            && wanted.as_ref() != Some(&previous)
+        {
+            self.drop_read_only_user(ctx, &previous).await?;
+            changes.push(format!("dropped read-only user {previous}"));
+        }
+        status.read_only_user = None;
+        let (Some(role), Some(spec)) = (wanted, self.spec().readOnlyUser.as_ref()) else {
+            return Ok(changes);
+        };
+
+        let templates = BTreeMap::new();
+        let current = status.read_only_password_secret_name.clone();
+        let login = LoginSecret {
+            namespace: self.namespace().unwrap(),
+            name: &spec.password_secret_name,
+            current: current.as_deref(),
+            owner: self.owner_ref(&()),
+            server: &self.spec().server,
+            role: role.clone(),
+            database: Some(name.clone()),
+            templates: &templates,
+        };
+
+        let (password, rotated) = match existing {
+            None => {
+                let password = generate_password();
+                // Marked together with the role, only roles carrying the marker are ever dropped.
+                let mut client = client.get().await?;
+                let tx = client.transaction().await?;
+                tx.batch_execute(&sql::create_user(&role, &password, spec.connection_limit)?)
+                    .await?;
+                tx.batch_execute(&sql::comment_on_role(&role, &ownership_comment(&uid))?)
+                    .await?;
+                tx.commit().await?;
+                // Recorded right away, so a failing create drops it again.
+                status.read_only_user = Some(role.clone());
+                changes.push(format!("created read-only user {role}"));
+                (password, false)
+            }
+            Some(connection_limit) => {
+                if connection_limit != spec.connection_limit {
+                    client
+                        .execute(
+                            &sql::alter_role_connection_limit(&role, spec.connection_limit)?,
+                            &[],
+                        )
+                        .await?;
+                    changes.push(format!("read-only user {role} connection limit"));
+                }
+                // Without the secret nobody knows the password, so set a new one.
+                match login.password(ctx).await? {
+                    Some(password) => (password, false),
+                    None => (generate_password(), true),
+                }
+            }
+        };
+        // A new password is published first, the secret in use keeps working until the role has it.
+        let published = login.publish(ctx, password.clone()).await?;
+        if rotated {
+            let altered = client
+                .execute(&sql::alter_role_password(&role, &password)?, &[])
+                .await;
+            if let Err(err) = altered {
+                login.discard(ctx, published).await?;
+                return Err(err);
+            }
+            changes.push(format!("read-only user {role} password rotated"));
+        } else if published.is_some() && changes.is_empty() {
+            changes.push(format!("read-only user {role} secret updated"));
+        }
+        status.read_only_password_secret_name = Some(login.switch_over(ctx, published).await?);
+
+        let pool = ctx.postgresql_databases.get(&self.spec().server, &name)?;
+        let schemas = pool
+            .query(
+                &format!("SELECT n.nspname FROM pg_namespace n WHERE {USER_SCHEMA}"),
+                &[],
             )
-            .await
-            .map_err(Error::KubeError)?;
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let grant = AppliedGrant {
+            user: role.clone(),
+            database: name.clone(),
+            owner: owner.to_string(),
+            schemas,
+            table_privileges: vec!["SELECT".to_string()],
+            sequence_privileges: vec!["SELECT".to_string()],
+        };
+        let mut client = pool.get().await?;
+        let tx = client.transaction().await?;
+        for statement in grant_statements(&grant)? {
+            tx.batch_execute(&statement).await?;
+        }
+        tx.commit().await?;
+
+        status.read_only_user = Some(role);
+        Ok(changes)
+    }
+}
 
-        if !ctx.postgresql_clients.contains_key(&self.spec().server) {
-            return Err(Error::PostgresqlUnknownServer);
+impl Managed for Database {
+    type Observed = ObservedDatabase;
+
+    fn backend(&self) -> Backend {
+        Backend::Postgresql(self.spec().server.clone())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name_any() == "illegal" {
+            return Err(Error::PostgresqlIllegalDatabase);
         }
+        sql::check_name(&self.spec().name)?;
+        if let Some(name) = self
+            .spec()
+            .readOnlyUser
+            .as_ref()
+            .and_then(|user| user.name.as_ref())
+        {
+            sql::check_name(name)?;
+        }
+        Ok(())
+    }
+
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletionPolicy
+    }
 
+    fn identity(&self, settings: &Settings, _status: &DatabaseStatus) -> Option<String> {
+        self.global_name(settings).ok().flatten()
+    }
+
+    fn global_name(&self, settings: &Settings) -> Result<Option<String>> {
+        Ok(Some(format!(
+            "{}/{}",
+            self.spec().server,
+            self.backend_name(settings)?
+        )))
+    }
+
+    /// An existing database is taken over as it is, the update brings it in line with the spec.
+    async fn adopt(&self, ctx: &Context, status: &mut DatabaseStatus) -> Result<bool> {
+        if self.observe(ctx).await?.is_none() {
+            return Ok(false);
+        }
+        status.backend_name = Some(self.backend_name(&ctx.settings)?);
+        Ok(true)
+    }
+
+    async fn marker(&self, ctx: &Context) -> Result<Option<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let row = ctx.postgresql_clients[&self.spec().server]
+            .query_opt(
+                "SELECT shobj_description(oid, 'pg_database') FROM pg_database WHERE datname = $1",
+                &[&name],
+            )
+            .await?;
+        let comment: Option<String> = row.and_then(|row| row.get(0));
+        Ok(comment
+            .as_deref()
+            .and_then(ownership_marker)
+            .map(str::to_string))
+    }
+
+    async fn mark(&self, ctx: &Context, uid: &str) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
         ctx.postgresql_clients[&self.spec().server]
-            .execute(&format!("DROP DATABASE {}", self.spec().name.clone()), &[])
+            .execute(
+                &sql::comment_on_database(&name, &ownership_comment(uid))?,
+                &[],
+            )
             .await?;
+        Ok(())
+    }
 
-        Ok(Action::await_change())
+    async fn observe(&self, ctx: &Context) -> Result<Option<ObservedDatabase>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let row = ctx.postgresql_clients[&self.spec().server]
+            .query_opt(
+                "SELECT datconnlimit, pg_get_userbyid(datdba) FROM pg_database WHERE datname = $1",
+                &[&name],
+            )
+            .await?;
+
+        Ok(row.map(|row| ObservedDatabase {
+            connection_limit: row.get(0),
+            owner: row.get(1),
+        }))
     }
-}
 
-#[instrument(skip(ctx, database), fields(trace_id))]
-async fn reconcile(database: Arc<Database>, ctx: Arc<Context>) -> Result<Action> {
-    let oref = database.object_ref(&());
-
-    let trace_id = telemetry::get_trace_id();
-    if trace_id != opentelemetry::trace::TraceId::INVALID {
-        Span::current().record("trace_id", field::display(&trace_id));
-    }
-    let _timer = ctx.metrics.reconcile.count_and_measure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        &trace_id,
-    );
-    ctx.diagnostics.write().await.last_event = Utc::now();
-    let ns = database.namespace().unwrap();
-    let databases: Api<Database> = Api::namespaced(ctx.kube_client.clone(), &ns);
-
-    info!("Reconciling Database \"{}\" in {}", database.name_any(), ns);
-    finalizer(&databases, FINALIZER, database, |event| async {
-        match event {
-            Finalizer::Apply(database) => database.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(database) => database.cleanup(ctx.clone()).await,
-        }
-    })
-    .await
-    .map_err(|e| Error::FinalizerError(Box::new(e)))
-}
+    async fn create(&self, ctx: &Context, status: &mut DatabaseStatus) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        let user = self.owner(ctx).await?;
+        let owner = user.backend_name(&ctx.settings)?;
 
-fn error_policy(database: Arc<Database>, error: &Error, ctx: Arc<Context>) -> Action {
-    error!("reconcile failed: {:?}", error);
-    let oref = database.object_ref(&());
-
-    ctx.metrics.reconcile.set_failure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        database.name_any(),
-        error,
-    );
-    Action::requeue(Duration::from_secs(5 * 60))
-}
+        ctx.postgresql_clients[&self.spec().server]
+            .execute(
+                &sql::create_database(&name, &owner, self.spec().connectionLimit)?,
+                &[],
+            )
+            .await?;
 
-/// Initialize the controller and shared state (given the crd is installed)
-pub async fn run(context: Arc<Context>, kube_client: KubeClient) -> Result<(), Error> {
-    let databases = Api::<Database>::all(kube_client.clone());
-    match databases.list(&ListParams::default().limit(1)).await {
-        Err(err) => return Err(Error::MissingCrds(err)),
-        Ok(_) => info!("CRDs for Database are installed!"),
-    };
-
-    info!("Starting controller");
-
-    Controller::new(databases, Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
+        let result = async {
+            // Marked first, so a later pass recognises the database even when this one fails to record it.
+            self.mark(ctx, &self.uid().unwrap_or_default()).await?;
+            status.reconcile.marked = true;
+            self.sync_extensions(ctx, status).await?;
+            self.sync_read_only_user(ctx, &owner, status).await
+        }
         .await;
+        if let Err(err) = result {
+            // The next attempt starts over, instead of finding a database it didn't create.
+            self.drop_database(ctx, &name).await?;
+            if let Some(role) = status.read_only_user.take()
+                && self.owns_read_only_user(ctx, &role).await?
+            {
+                ctx.postgresql_clients[&self.spec().server]
+                    .execute(&sql::drop_user_if_exists(&role)?, &[])
+                    .await?;
+            }
+            return Err(err);
+        }
+
+        status.backend_name = Some(name);
+        status.owner = owner;
+        status.connection_limit = self.spec().connectionLimit;
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        ctx: &Context,
+        observed: ObservedDatabase,
+        status: &mut DatabaseStatus,
+    ) -> Result<Vec<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let user = self.owner(ctx).await?;
+        let owner = user.backend_name(&ctx.settings)?;
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        let mut changes = Vec::new();
+
+        if observed.connection_limit != self.spec().connectionLimit {
+            client
+                .execute(
+                    &sql::alter_database_connection_limit(&name, self.spec().connectionLimit)?,
+                    &[],
+                )
+                .await?;
+            changes.push(format!(
+                "connection limit {} -> {}",
+                observed.connection_limit,
+                self.spec().connectionLimit
+            ));
+        }
 
-    Ok(())
+        if observed.owner != owner {
+            client
+                .execute(&sql::alter_database_owner(&name, &owner)?, &[])
+                .await?;
+            changes.push(format!("owner {} -> {}", observed.owner, owner));
+        }
+
+        changes.extend(self.sync_extensions(ctx, status).await?);
+        changes.extend(self.sync_read_only_user(ctx, &owner, status).await?);
+
+        status.backend_name = Some(name);
+        status.owner = owner;
+        status.connection_limit = self.spec().connectionLimit;
+        Ok(changes)
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        self.drop_database(ctx, &name).await?;
+        // Its privileges went with the database, so the role can go as well.
+        if let Some(role) = self.status.as_ref().and_then(|s| s.read_only_user.clone())
+            && self.owns_read_only_user(ctx, &role).await?
+        {
+            ctx.postgresql_clients[&self.spec().server]
+                .execute(&sql::drop_user_if_exists(&role)?, &[])
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn orphan(&self, ctx: &Context, note: &str) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        client
+            .execute(&sql::comment_on_database(&name, note)?, &[])
+            .await?;
+        if let Some(role) = self.status.as_ref().and_then(|s| s.read_only_user.clone()) {
+            client
+                .execute(&sql::comment_on_role(&role, note)?, &[])
+                .await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,859 @@
+//! Logical dumps, written as plain SQL the way `pg_dump --no-owner` would.
+//!
+//! The schema is rebuilt from the catalog with the server's own `pg_get_*`
+//! functions, and table data is streamed with `COPY`. Everything is read in a
+//! single repeatable read transaction, so the dump is a consistent snapshot.
+//!
+//! Covered are schemas, extensions, enum types, sequences, tables, functions,
+//! constraints, indexes, views, triggers, row level security, comments, grants
+//! and the database owner's default privileges in schemas. Ownership is left
+//! to whoever restores the dump, everything is owned by the role replaying it. A database holding anything else that carries data or shapes
+//! it, such as partitioned tables or domains, fails the dump instead of losing it.
+use crate::{Error, Result, garage::Upload};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::future::Future;
+use std::pin::pin;
+use tokio_postgres::{Client, IsolationLevel, Transaction};
+
+/// Where a dump is written to.
+pub trait Output: Send {
+    fn write(&mut self, data: &[u8]) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl Output for Upload {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        Upload::write(self, data)
+            .await
+            .map_err(Error::GarageClientError)
+    }
+}
+
+/// Session settings the dump relies on when it's replayed.
+const HEADER: &str = "SET statement_timeout = 0;
+SET client_encoding = 'UTF8';
+SET standard_conforming_strings = on;
+SET check_function_bodies = false;
+SET client_min_messages = warning;
+SELECT pg_catalog.set_config('search_path', '', false);
+";
+
+/// Schemas that belong to the user, as opposed to the system catalogs.
+pub(super) const USER_SCHEMA: &str =
+    r"n.nspname <> 'information_schema' AND n.nspname NOT LIKE 'pg\_%'";
+
+/// Objects created by extensions come back with `CREATE EXTENSION`.
+pub(super) fn not_extension_member(catalog: &str, oid: &str) -> String {
+    format!(
+        "NOT EXISTS (SELECT 1 FROM pg_depend d WHERE d.classid = '{catalog}'::regclass AND d.objid = {oid} AND d.deptype = 'e')"
+    )
+}
+
+/// Each section is a catalog query returning complete statements, in replay order.
+fn sections() -> Vec<(&'static str, String)> {
+    let user_table = format!(
+        "c.relkind = 'r' AND NOT c.relispartition AND {USER_SCHEMA} AND {}",
+        not_extension_member("pg_class", "c.oid")
+    );
+
+    vec![
+        (
+            "Schemas",
+            format!(
+                "SELECT format('CREATE SCHEMA IF NOT EXISTS %I;', n.nspname)
+                FROM pg_namespace n
+                WHERE {USER_SCHEMA} AND n.nspname <> 'public' AND {}
+                ORDER BY n.oid",
+                not_extension_member("pg_namespace", "n.oid")
+            ),
+        ),
+        (
+            "Extensions",
+            "SELECT format('CREATE EXTENSION IF NOT EXISTS %I WITH SCHEMA %I;', e.extname, n.nspname)
+            FROM pg_extension e
+            JOIN pg_namespace n ON n.oid = e.extnamespace
+            WHERE e.extname <> 'plpgsql'
+            ORDER BY e.oid"
+                .to_string(),
+        ),
+        (
+            "Types",
+            format!(
+                "SELECT format('CREATE TYPE %I.%I AS ENUM (%s);', n.nspname, t.typname,
+                    string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder))
+                FROM pg_type t
+                JOIN pg_namespace n ON n.oid = t.typnamespace
+                JOIN pg_enum e ON e.enumtypid = t.oid
+                WHERE {USER_SCHEMA} AND {}
+                GROUP BY t.oid, n.nspname, t.typname
+                ORDER BY t.oid",
+                not_extension_member("pg_type", "t.oid")
+            ),
+        ),
+        (
+            "Sequences",
+            format!(
+                "SELECT format('CREATE SEQUENCE %I.%I AS %s START WITH %s INCREMENT BY %s MINVALUE %s MAXVALUE %s CACHE %s%s;',
+                    n.nspname, c.relname, format_type(s.seqtypid, NULL), s.seqstart, s.seqincrement,
+                    s.seqmin, s.seqmax, s.seqcache, CASE WHEN s.seqcycle THEN ' CYCLE' ELSE ' NO CYCLE' END)
+                FROM pg_sequence s
+                JOIN pg_class c ON c.oid = s.seqrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE {USER_SCHEMA} AND {}
+                AND NOT EXISTS (SELECT 1 FROM pg_depend d WHERE d.objid = c.oid AND d.deptype = 'i')
+                ORDER BY c.oid",
+                not_extension_member("pg_class", "c.oid")
+            ),
+        ),
+        (
+            "Tables",
+            format!(
+                r"SELECT format(E'CREATE TABLE %I.%I (\n%s\n);', n.nspname, c.relname,
+                    coalesce(string_agg(format('    %I %s%s%s', a.attname, format_type(a.atttypid, a.atttypmod),
+                        CASE a.attidentity
+                            WHEN 'a' THEN ' GENERATED ALWAYS AS IDENTITY'
+                            WHEN 'd' THEN ' GENERATED BY DEFAULT AS IDENTITY'
+                            ELSE ''
+                        END || CASE a.attgenerated
+                            WHEN 's' THEN format(' GENERATED ALWAYS AS (%s) STORED', pg_get_expr(ad.adbin, ad.adrelid))
+                            WHEN 'v' THEN format(' GENERATED ALWAYS AS (%s) VIRTUAL', pg_get_expr(ad.adbin, ad.adrelid))
+                            ELSE ''
+                        END,
+                        CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END), E',\n' ORDER BY a.attnum), ''))
+                FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                LEFT JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+                LEFT JOIN pg_attrdef ad ON ad.adrelid = a.attrelid AND ad.adnum = a.attnum
+                WHERE {user_table}
+                GROUP BY c.oid, n.nspname, c.relname
+                ORDER BY c.oid"
+            ),
+        ),
+        (
+            "Functions",
+            format!(
+                "SELECT pg_get_functiondef(p.oid) || ';'
+                FROM pg_proc p
+                JOIN pg_namespace n ON n.oid = p.pronamespace
+                WHERE p.prokind IN ('f', 'p') AND {USER_SCHEMA} AND {}
+                ORDER BY p.oid",
+                not_extension_member("pg_proc", "p.oid")
+            ),
+        ),
+        (
+            "Defaults",
+            format!(
+                "SELECT format('ALTER TABLE ONLY %I.%I ALTER COLUMN %I SET DEFAULT %s;',
+                    n.nspname, c.relname, a.attname, pg_get_expr(ad.adbin, ad.adrelid))
+                FROM pg_attrdef ad
+                JOIN pg_attribute a ON a.attrelid = ad.adrelid AND a.attnum = ad.adnum
+                JOIN pg_class c ON c.oid = ad.adrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE a.attgenerated = '' AND {user_table}
+                ORDER BY c.oid, a.attnum"
+            ),
+        ),
+        (
+            "Sequence ownership",
+            format!(
+                "SELECT format('ALTER SEQUENCE %I.%I OWNED BY %I.%I.%I;',
+                    sn.nspname, s.relname, n.nspname, c.relname, a.attname)
+                FROM pg_depend d
+                JOIN pg_class s ON s.oid = d.objid AND s.relkind = 'S'
+                JOIN pg_namespace sn ON sn.oid = s.relnamespace
+                JOIN pg_class c ON c.oid = d.refobjid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum = d.refobjsubid
+                WHERE d.classid = 'pg_class'::regclass AND d.refclassid = 'pg_class'::regclass
+                AND d.deptype = 'a' AND {user_table}
+                ORDER BY s.oid"
+            ),
+        ),
+    ]
+}
+
+/// Depth of each user view in the views it selects from, views are created in that order.
+///
+/// `CREATE OR REPLACE VIEW` can make a view depend on one with a higher oid.
+fn view_levels(user_view: &str) -> String {
+    format!(
+        "WITH RECURSIVE refs AS (
+            SELECT DISTINCT r.ev_class AS view, d.refobjid AS ref
+            FROM pg_rewrite r
+            JOIN pg_depend d ON d.classid = 'pg_rewrite'::regclass AND d.objid = r.oid
+                AND d.refclassid = 'pg_class'::regclass AND d.refobjid <> r.ev_class
+        ), levels AS (
+            SELECT c.oid AS view, 0 AS level
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE {user_view}
+            UNION ALL
+            SELECT refs.view, levels.level + 1
+            FROM levels
+            JOIN refs ON refs.ref = levels.view
+        )
+        SELECT view, max(level) AS level FROM levels GROUP BY view"
+    )
+}
+
+/// Grants of every object the dump creates, the owner's own privileges come with owning it.
+///
+/// Objects with explicit privileges lose the default ones of `PUBLIC` first, as they did on the source.
+fn grants() -> String {
+    format!(
+        "WITH objects AS (
+            SELECT 1 AS step, n.oid, format('SCHEMA %I', n.nspname) AS object, n.nspacl AS acl, n.nspowner AS owner
+            FROM pg_namespace n
+            WHERE {USER_SCHEMA} AND {}
+            UNION ALL
+            SELECT 2, c.oid, format('%s %I.%I', CASE c.relkind WHEN 'S' THEN 'SEQUENCE' ELSE 'TABLE' END, n.nspname, c.relname),
+                c.relacl, c.relowner
+            FROM pg_class c
+            JOIN pg_namespace n ON n.oid = c.relnamespace
+            WHERE c.relkind IN ('r', 'v', 'm', 'S') AND NOT c.relispartition AND {USER_SCHEMA} AND {}
+            UNION ALL
+            SELECT 3, p.oid, format('ROUTINE %s', p.oid::regprocedure), p.proacl, p.proowner
+            FROM pg_proc p
+            JOIN pg_namespace n ON n.oid = p.pronamespace
+            WHERE p.prokind IN ('f', 'p') AND {USER_SCHEMA} AND {}
+            UNION ALL
+            SELECT 4, t.oid, format('TYPE %s', t.oid::regtype), t.typacl, t.typowner
+            FROM pg_type t
+            JOIN pg_namespace n ON n.oid = t.typnamespace
+            WHERE t.typtype = 'e' AND {USER_SCHEMA} AND {}
+        )
+        SELECT statement FROM (
+            SELECT o.step, o.oid, 0 AS entry, format('REVOKE ALL ON %s FROM PUBLIC;', o.object) AS statement
+            FROM objects o
+            WHERE o.acl IS NOT NULL
+            UNION ALL
+            SELECT o.step, o.oid, 1, format('GRANT %s ON %s TO %s%s;', a.privilege_type, o.object,
+                CASE WHEN a.grantee = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(a.grantee)) END,
+                CASE WHEN a.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END)
+            FROM objects o
+            CROSS JOIN LATERAL aclexplode(o.acl) a
+            WHERE a.grantee <> o.owner
+        ) grants
+        ORDER BY step, oid, entry, statement",
+        not_extension_member("pg_namespace", "n.oid"),
+        not_extension_member("pg_class", "c.oid"),
+        not_extension_member("pg_proc", "p.oid"),
+        not_extension_member("pg_type", "t.oid"),
+    )
+}
+
+/// Objects the dump creates that can carry a comment, by their `pg_description` key.
+fn commentable() -> String {
+    let user_relation = format!(
+        "NOT c.relispartition AND {USER_SCHEMA} AND {}",
+        not_extension_member("pg_class", "c.oid")
+    );
+    format!(
+        "SELECT 'pg_namespace'::regclass AS classoid, n.oid AS objoid, 0 AS objsubid, format('SCHEMA %I', n.nspname) AS object
+        FROM pg_namespace n
+        WHERE n.nspname <> 'public' AND {USER_SCHEMA} AND {}
+        UNION ALL
+        SELECT 'pg_class'::regclass, c.oid, 0, format('%s %I.%I', CASE c.relkind WHEN 'r' THEN 'TABLE' WHEN 'v' THEN 'VIEW'
+                WHEN 'm' THEN 'MATERIALIZED VIEW' WHEN 'S' THEN 'SEQUENCE' ELSE 'INDEX' END, n.nspname, c.relname)
+        FROM pg_class c
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE c.relkind IN ('r', 'v', 'm', 'S', 'i') AND {user_relation}
+        UNION ALL
+        SELECT 'pg_class'::regclass, c.oid, a.attnum, format('COLUMN %I.%I.%I', n.nspname, c.relname, a.attname)
+        FROM pg_attribute a
+        JOIN pg_class c ON c.oid = a.attrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE a.attnum > 0 AND NOT a.attisdropped AND c.relkind IN ('r', 'v', 'm') AND {user_relation}
+        UNION ALL
+        SELECT 'pg_proc'::regclass, p.oid, 0, format('ROUTINE %s', p.oid::regprocedure)
+        FROM pg_proc p
+        JOIN pg_namespace n ON n.oid = p.pronamespace
+        WHERE p.prokind IN ('f', 'p') AND {USER_SCHEMA} AND {}
+        UNION ALL
+        SELECT 'pg_type'::regclass, t.oid, 0, format('TYPE %s', t.oid::regtype)
+        FROM pg_type t
+        JOIN pg_namespace n ON n.oid = t.typnamespace
+        WHERE t.typtype = 'e' AND {USER_SCHEMA} AND {}
+        UNION ALL
+        SELECT 'pg_constraint'::regclass, con.oid, 0, format('CONSTRAINT %I ON %I.%I', con.conname, n.nspname, c.relname)
+        FROM pg_constraint con
+        JOIN pg_class c ON c.oid = con.conrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE con.contype IN ('p', 'u', 'c', 'x', 'f') AND con.conislocal AND c.relkind = 'r' AND {user_relation}
+        UNION ALL
+        SELECT 'pg_trigger'::regclass, t.oid, 0, format('TRIGGER %I ON %I.%I', t.tgname, n.nspname, c.relname)
+        FROM pg_trigger t
+        JOIN pg_class c ON c.oid = t.tgrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE NOT t.tgisinternal AND c.relkind = 'r' AND {user_relation}
+        UNION ALL
+        SELECT 'pg_policy'::regclass, p.oid, 0, format('POLICY %I ON %I.%I', p.polname, n.nspname, c.relname)
+        FROM pg_policy p
+        JOIN pg_class c ON c.oid = p.polrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE c.relkind = 'r' AND {user_relation}",
+        not_extension_member("pg_namespace", "n.oid"),
+        not_extension_member("pg_proc", "p.oid"),
+        not_extension_member("pg_type", "t.oid"),
+    )
+}
+
+/// Sections replayed after the data, so loading it isn't slowed down or blocked by them.
+///
+/// Row level security is enabled once the data is in, a forced policy would filter it otherwise.
+fn post_data_sections() -> Vec<(&'static str, String)> {
+    let user_table = format!(
+        "c.relkind = 'r' AND NOT c.relispartition AND {USER_SCHEMA} AND {}",
+        not_extension_member("pg_class", "c.oid")
+    );
+    let user_view = format!(
+        "c.relkind IN ('v', 'm') AND {USER_SCHEMA} AND {}",
+        not_extension_member("pg_class", "c.oid")
+    );
+    let view_levels = view_levels(&user_view);
+
+    vec![
+        (
+            "Sequence values",
+            format!(
+                "SELECT format('SELECT pg_catalog.setval(%L, %s, true);', format('%I.%I', n.nspname, c.relname), s.last_value)
+                FROM pg_sequences s
+                JOIN pg_namespace n ON n.nspname = s.schemaname
+                JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = s.sequencename
+                WHERE s.last_value IS NOT NULL AND {USER_SCHEMA} AND {}
+                ORDER BY c.oid",
+                not_extension_member("pg_class", "c.oid")
+            ),
+        ),
+        (
+            "Constraints",
+            format!(
+                "SELECT format('ALTER TABLE ONLY %I.%I ADD CONSTRAINT %I %s;',
+                    n.nspname, c.relname, con.conname, pg_get_constraintdef(con.oid))
+                FROM pg_constraint con
+                JOIN pg_class c ON c.oid = con.conrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE con.contype IN ('p', 'u', 'c', 'x', 'f') AND con.conislocal AND {user_table}
+                ORDER BY con.contype = 'f', con.oid"
+            ),
+        ),
+        (
+            "Indexes",
+            format!(
+                "SELECT pg_get_indexdef(i.indexrelid) || ';'
+                FROM pg_index i
+                JOIN pg_class c ON c.oid = i.indrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE {user_table} AND NOT EXISTS (
+                    SELECT 1 FROM pg_constraint con
+                    WHERE con.conindid = i.indexrelid AND con.conrelid = i.indrelid AND con.contype IN ('p', 'u', 'x')
+                )
+                ORDER BY i.indexrelid"
+            ),
+        ),
+        (
+            "Views",
+            format!(
+                r"SELECT format(E'CREATE %sVIEW %I.%I AS\n%s%s',
+                    CASE c.relkind WHEN 'm' THEN 'MATERIALIZED ' ELSE '' END, n.nspname, c.relname,
+                    rtrim(pg_get_viewdef(c.oid), ';'),
+                    CASE c.relkind WHEN 'm' THEN E'\nWITH NO DATA;' ELSE ';' END)
+                FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                JOIN ({view_levels}) l ON l.view = c.oid
+                WHERE {user_view}
+                ORDER BY l.level, c.oid"
+            ),
+        ),
+        (
+            "Materialized view data",
+            format!(
+                "SELECT format('REFRESH MATERIALIZED VIEW %I.%I;', n.nspname, c.relname)
+                FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                JOIN ({view_levels}) l ON l.view = c.oid
+                WHERE c.relkind = 'm' AND {user_view}
+                ORDER BY l.level, c.oid"
+            ),
+        ),
+        (
+            "Triggers",
+            format!(
+                "SELECT pg_get_triggerdef(t.oid) || ';'
+                FROM pg_trigger t
+                JOIN pg_class c ON c.oid = t.tgrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE NOT t.tgisinternal AND {user_table}
+                ORDER BY t.oid"
+            ),
+        ),
+        (
+            "Row level security",
+            format!(
+                "SELECT format('ALTER TABLE ONLY %I.%I %s ROW LEVEL SECURITY;', n.nspname, c.relname, s.action)
+                FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                CROSS JOIN LATERAL (VALUES (1, 'ENABLE', c.relrowsecurity), (2, 'FORCE', c.relforcerowsecurity))
+                    AS s(step, action, enabled)
+                WHERE s.enabled AND {user_table}
+                ORDER BY c.oid, s.step"
+            ),
+        ),
+        (
+            "Policies",
+            format!(
+                "SELECT format('CREATE POLICY %I ON %I.%I AS %s FOR %s TO %s%s%s;', p.polname, n.nspname, c.relname,
+                    CASE WHEN p.polpermissive THEN 'PERMISSIVE' ELSE 'RESTRICTIVE' END,
+                    CASE p.polcmd WHEN 'r' THEN 'SELECT' WHEN 'a' THEN 'INSERT' WHEN 'w' THEN 'UPDATE'
+                        WHEN 'd' THEN 'DELETE' ELSE 'ALL' END,
+                    (SELECT string_agg(CASE WHEN r.oid = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(r.oid)) END, ', ')
+                        FROM unnest(p.polroles) AS r(oid)),
+                    ' USING (' || pg_get_expr(p.polqual, p.polrelid) || ')',
+                    ' WITH CHECK (' || pg_get_expr(p.polwithcheck, p.polrelid) || ')')
+                FROM pg_policy p
+                JOIN pg_class c ON c.oid = p.polrelid
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE {user_table}
+                ORDER BY p.oid"
+            ),
+        ),
+        ("Grants", grants()),
+        (
+            "Default privileges",
+            "SELECT format('ALTER DEFAULT PRIVILEGES IN SCHEMA %I GRANT %s ON %s TO %s%s;', n.nspname, a.privilege_type,
+                CASE d.defaclobjtype WHEN 'r' THEN 'TABLES' WHEN 'S' THEN 'SEQUENCES' WHEN 'f' THEN 'FUNCTIONS'
+                    WHEN 'T' THEN 'TYPES' ELSE 'SCHEMAS' END,
+                CASE WHEN a.grantee = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(a.grantee)) END,
+                CASE WHEN a.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END)
+            FROM pg_default_acl d
+            JOIN pg_namespace n ON n.oid = d.defaclnamespace
+            CROSS JOIN LATERAL aclexplode(d.defaclacl) a
+            WHERE d.defaclrole = (SELECT datdba FROM pg_database WHERE datname = current_database())
+            AND a.grantee <> d.defaclrole
+            ORDER BY d.oid, a.privilege_type, a.grantee"
+                .to_string(),
+        ),
+        (
+            "Comments",
+            format!(
+                "SELECT format('COMMENT ON %s IS %L;', o.object, d.description)
+                FROM pg_description d
+                JOIN ({}) o ON o.classoid = d.classoid AND o.objoid = d.objoid AND o.objsubid = d.objsubid
+                ORDER BY d.classoid, d.objoid, d.objsubid",
+                commentable()
+            ),
+        ),
+    ]
+}
+
+/// Objects the dump can't reproduce, described for the error.
+fn unsupported() -> String {
+    let user_class = format!(
+        "{USER_SCHEMA} AND {}",
+        not_extension_member("pg_class", "c.oid")
+    );
+    format!(
+        "SELECT format('%s %I.%I', CASE WHEN c.relispartition THEN 'partition'
+                WHEN c.relkind = 'p' THEN 'partitioned table' ELSE 'foreign table' END, n.nspname, c.relname)
+        FROM pg_class c
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE (c.relkind IN ('p', 'f') OR c.relispartition) AND {user_class}
+        UNION ALL
+        SELECT format('inheriting table %I.%I', n.nspname, c.relname)
+        FROM pg_inherits i
+        JOIN pg_class c ON c.oid = i.inhrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE NOT c.relispartition AND {user_class}
+        UNION ALL
+        SELECT format('%s %I.%I', CASE t.typtype WHEN 'd' THEN 'domain' WHEN 'c' THEN 'composite type'
+                WHEN 'r' THEN 'range type' ELSE 'base type' END, n.nspname, t.typname)
+        FROM pg_type t
+        JOIN pg_namespace n ON n.oid = t.typnamespace
+        WHERE t.typtype IN ('b', 'c', 'd', 'r') AND {USER_SCHEMA} AND {}
+        -- Row types of tables and the array types of everything come along on their own.
+        AND (t.typtype <> 'c' OR EXISTS (SELECT 1 FROM pg_class r WHERE r.oid = t.typrelid AND r.relkind = 'c'))
+        AND NOT EXISTS (SELECT 1 FROM pg_type e WHERE e.typarray = t.oid)
+        UNION ALL
+        SELECT format('column %I.%I.%I with collation %s', n.nspname, c.relname, a.attname, a.attcollation::regcollation)
+        FROM pg_attribute a
+        JOIN pg_type t ON t.oid = a.atttypid
+        JOIN pg_class c ON c.oid = a.attrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE a.attnum > 0 AND NOT a.attisdropped AND a.attcollation <> t.typcollation
+        AND c.relkind = 'r' AND {user_class}
+        UNION ALL
+        SELECT format('rule %I on %I.%I', r.rulename, n.nspname, c.relname)
+        FROM pg_rewrite r
+        JOIN pg_class c ON c.oid = r.ev_class
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE r.rulename <> '_RETURN' AND {user_class}
+        UNION ALL
+        SELECT format('privileges on columns of %I.%I', n.nspname, c.relname)
+        FROM pg_class c
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE EXISTS (SELECT 1 FROM pg_attribute a WHERE a.attrelid = c.oid AND a.attacl IS NOT NULL)
+        AND c.relkind IN ('r', 'v', 'm') AND {user_class}
+        UNION ALL
+        SELECT format('default privileges of %s%s', pg_get_userbyid(d.defaclrole),
+            CASE WHEN d.defaclnamespace = 0 THEN ' in every schema' ELSE '' END)
+        FROM pg_default_acl d
+        WHERE d.defaclnamespace = 0
+        OR d.defaclrole <> (SELECT datdba FROM pg_database WHERE datname = current_database())
+        UNION ALL
+        SELECT format('%s large objects', count(*))
+        FROM pg_largeobject_metadata
+        HAVING count(*) > 0
+        UNION ALL
+        SELECT format('comment on %s %s', d.classoid::regclass, d.objoid)
+        FROM pg_description d
+        WHERE d.objoid >= 16384 AND d.classoid NOT IN ('pg_extension'::regclass, 'pg_largeobject'::regclass)
+        AND NOT EXISTS (SELECT 1 FROM pg_depend e WHERE e.classid = d.classoid AND e.objid = d.objoid AND e.deptype = 'e')
+        AND NOT EXISTS (
+            SELECT 1 FROM ({}) o
+            WHERE o.classoid = d.classoid AND o.objoid = d.objoid AND o.objsubid = d.objsubid
+        )",
+        not_extension_member("pg_type", "t.oid"),
+        commentable()
+    )
+}
+
+/// Fail when the database holds objects the dump would silently leave out.
+async fn check_supported(transaction: &Transaction<'_>) -> Result<()> {
+    let objects: Vec<String> = transaction
+        .query(&unsupported(), &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    if !objects.is_empty() {
+        return Err(Error::DumpUnsupported(objects.join(", ")));
+    }
+    Ok(())
+}
+
+/// Write a section of complete statements under its title, nothing at all without statements.
+async fn write_statements(
+    title: &str,
+    statements: Vec<String>,
+    output: &mut impl Output,
+) -> Result<()> {
+    if statements.is_empty() {
+        return Ok(());
+    }
+    output.write(format!("\n-- {title}\n").as_bytes()).await?;
+    for statement in statements {
+        output.write(statement.as_bytes()).await?;
+        output.write(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Write the rows of `table`, in COPY text format and ended the way psql expects it.
+async fn write_copy(
+    table: &str,
+    columns: &str,
+    rows: impl Stream<Item = Result<Bytes, tokio_postgres::Error>>,
+    output: &mut impl Output,
+) -> Result<()> {
+    output
+        .write(format!("COPY {table} ({columns}) FROM stdin;\n").as_bytes())
+        .await?;
+    let mut rows = pin!(rows);
+    while let Some(chunk) = rows.next().await {
+        output.write(&chunk?).await?;
+    }
+    output.write(b"\\.\n").await
+}
+
+async fn write_sections(
+    transaction: &Transaction<'_>,
+    sections: Vec<(&'static str, String)>,
+    output: &mut impl Output,
+) -> Result<()> {
+    for (title, query) in sections {
+        let statements = transaction
+            .query(&query, &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        write_statements(title, statements, output).await?;
+    }
+    Ok(())
+}
+
+async fn write_data(transaction: &Transaction<'_>, output: &mut impl Output) -> Result<()> {
+    let tables = transaction
+        .query(
+            &format!(
+                "SELECT format('%I.%I', n.nspname, c.relname),
+                    string_agg(quote_ident(a.attname), ', ' ORDER BY a.attnum)
+                FROM pg_class c
+                JOIN pg_namespace n ON n.oid = c.relnamespace
+                JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped AND a.attgenerated = ''
+                WHERE c.relkind = 'r' AND NOT c.relispartition AND {USER_SCHEMA} AND {}
+                GROUP BY c.oid, n.nspname, c.relname
+                ORDER BY c.oid",
+                not_extension_member("pg_class", "c.oid")
+            ),
+            &[],
+        )
+        .await?;
+
+    output.write(b"\n-- Data\n").await?;
+    for row in tables {
+        let table: String = row.get(0);
+        let columns: String = row.get(1);
+        let rows = transaction
+            .copy_out(&format!("COPY {table} ({columns}) TO STDOUT"))
+            .await?;
+        write_copy(&table, &columns, rows, output).await?;
+    }
+    Ok(())
+}
+
+/// Write a dump of the database `client` is connected to into `output`.
+pub async fn dump(client: &mut Client, output: &mut impl Output) -> Result<()> {
+    let transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+    // With an empty search path every name the server prints is schema qualified.
+    transaction
+        .batch_execute("SET LOCAL search_path = ''")
+        .await?;
+    check_supported(&transaction).await?;
+
+    output.write(HEADER.as_bytes()).await?;
+    write_sections(&transaction, sections(), output).await?;
+    write_data(&transaction, output).await?;
+    write_sections(&transaction, post_data_sections(), output).await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::resources::postgresql::statements::{Item, Splitter};
+    use tokio_postgres::NoTls;
+
+    impl Output for Vec<u8> {
+        async fn write(&mut self, data: &[u8]) -> Result<()> {
+            self.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Statements of a dump the way a restore reads them back, with COPY data in one piece.
+    fn split(script: &[u8]) -> Vec<String> {
+        let mut splitter = Splitter::default();
+        splitter.push(script);
+        let mut items = Vec::new();
+        let mut data = Vec::new();
+        while let Some(item) = splitter.next_item(true).unwrap() {
+            match item {
+                Item::Statement(statement) | Item::Copy(statement) => items.push(statement),
+                Item::CopyData(chunk) => data.extend(chunk),
+                Item::CopyDone => items.push(String::from_utf8(std::mem::take(&mut data)).unwrap()),
+            }
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn dump_output_splits_back_into_its_statements() {
+        let function = "CREATE OR REPLACE FUNCTION app.note(id integer)
+ RETURNS text
+ LANGUAGE sql
+AS $function$SELECT 'a;b' || $1$function$
+;";
+        let policy = "CREATE POLICY \"own;rows\" ON app.notes AS PERMISSIVE FOR ALL TO PUBLIC USING ((owner = CURRENT_USER));";
+        let comment = "COMMENT ON TABLE app.notes IS 'it''s; notes';";
+        let rows = futures::stream::iter([
+            Ok(Bytes::from_static(b"1\ta;b\n2\t")),
+            Ok(Bytes::from_static(b"back\\\\slash\n")),
+        ]);
+
+        let mut output = Vec::new();
+        output.write(HEADER.as_bytes()).await.unwrap();
+        write_statements("Functions", vec![function.to_string()], &mut output)
+            .await
+            .unwrap();
+        write_statements("Empty", vec![], &mut output)
+            .await
+            .unwrap();
+        write_copy("app.notes", "id, note", rows, &mut output)
+            .await
+            .unwrap();
+        write_statements(
+            "Policies",
+            vec![policy.to_string(), comment.to_string()],
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let items = split(&output);
+        assert!(!String::from_utf8_lossy(&output).contains("-- Empty"));
+        assert_eq!(items.len(), HEADER.matches(';').count() + 5);
+        assert_eq!(
+            items[items.len() - 5..],
+            [
+                function.trim_end_matches(';'),
+                "COPY app.notes (id, note) FROM stdin",
+                "1\ta;b\n2\tback\\\\slash\n",
+                policy.trim_end_matches(';'),
+                comment.trim_end_matches(';'),
+            ]
+        );
+    }
+
+    async fn connect(database: &str) -> Client {
+        let url = std::env::var("POSTGRESQL_TEST_URL").unwrap();
+        let (client, connection) =
+            tokio_postgres::connect(&format!("{url} dbname={database}"), NoTls)
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        client
+    }
+
+    /// What a restore has to bring back, read from the catalog.
+    async fn summary(client: &Client) -> Vec<String> {
+        let queries = [
+            "SELECT format('%s %s', id, note) FROM app.notes ORDER BY id",
+            "SELECT string_agg(format('%s %s %s', policyname, cmd, qual), ', ' ORDER BY policyname) FROM pg_policies",
+            "SELECT format('%s %s', relrowsecurity, relforcerowsecurity) FROM pg_class WHERE oid = 'app.notes'::regclass",
+            "SELECT string_agg(format('%s %s', c.relname, c.relacl), ', ' ORDER BY c.relname)
+                FROM pg_class c WHERE c.relnamespace = 'app'::regnamespace AND c.relacl IS NOT NULL",
+            "SELECT string_agg(description, ', ' ORDER BY description) FROM pg_description WHERE objoid >= 16384",
+            "SELECT string_agg(format('%s %s', defaclobjtype, defaclacl), ', ') FROM pg_default_acl",
+            "SELECT string_agg(summary, ', ') FROM app.latest",
+        ];
+        let mut summary = Vec::new();
+        for query in queries {
+            for row in client.query(query, &[]).await.unwrap() {
+                summary.push(row.get::<_, Option<String>>(0).unwrap_or_default());
+            }
+        }
+        summary
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL server at POSTGRESQL_TEST_URL"]
+    async fn dump_round_trips() {
+        let admin = connect("postgres").await;
+        // Database statements can't share the implicit transaction of a batch.
+        for statement in [
+            "DROP DATABASE IF EXISTS dump_source",
+            "DROP DATABASE IF EXISTS dump_target",
+            "DROP ROLE IF EXISTS dump_reader",
+            "CREATE ROLE dump_reader",
+            "CREATE DATABASE dump_source",
+            "CREATE DATABASE dump_target",
+        ] {
+            admin.batch_execute(statement).await.unwrap();
+        }
+
+        let mut source = connect("dump_source").await;
+        source
+            .batch_execute(
+                "CREATE SCHEMA app;
+                CREATE TABLE app.notes (id int PRIMARY KEY, note text, owner name DEFAULT current_user);
+                INSERT INTO app.notes (id, note) VALUES (1, 'a;b'), (2, E'tab\\there'), (3, 'back\\slash');
+                COMMENT ON TABLE app.notes IS 'it''s notes';
+                COMMENT ON COLUMN app.notes.note IS 'the note';
+                ALTER TABLE app.notes ENABLE ROW LEVEL SECURITY;
+                ALTER TABLE app.notes FORCE ROW LEVEL SECURITY;
+                CREATE POLICY own ON app.notes USING (owner = current_user);
+                COMMENT ON POLICY own ON app.notes IS 'only your own';
+                GRANT USAGE ON SCHEMA app TO dump_reader;
+                GRANT SELECT ON app.notes TO dump_reader;
+                ALTER DEFAULT PRIVILEGES IN SCHEMA app GRANT SELECT ON TABLES TO dump_reader;
+                CREATE VIEW app.latest AS SELECT text 'none' AS summary;
+                CREATE VIEW app.summary AS SELECT count(*) AS notes FROM app.notes;
+                CREATE OR REPLACE VIEW app.latest AS SELECT format('%s notes', notes) AS summary FROM app.summary;",
+            )
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        dump(&mut source, &mut output).await.unwrap();
+
+        let mut target = connect("dump_target").await;
+        let tx = target.transaction().await.unwrap();
+        let mut splitter = Splitter::default();
+        splitter.push(&output);
+        let mut copy = None;
+        while let Some(item) = splitter.next_item(true).unwrap() {
+            match item {
+                Item::Statement(statement) => tx.batch_execute(&statement).await.unwrap(),
+                Item::Copy(statement) => {
+                    copy = Some(Box::pin(tx.copy_in::<_, Bytes>(&statement).await.unwrap()))
+                }
+                Item::CopyData(data) => {
+                    use futures::SinkExt;
+                    copy.as_mut()
+                        .unwrap()
+                        .send(Bytes::from(data))
+                        .await
+                        .unwrap();
+                }
+                Item::CopyDone => {
+                    copy.take().unwrap().as_mut().finish().await.unwrap();
+                }
+            }
+        }
+        tx.commit().await.unwrap();
+
+        assert_eq!(summary(&source).await, summary(&target).await);
+        drop((source, target));
+        for statement in [
+            "DROP DATABASE dump_source WITH (FORCE)",
+            "DROP DATABASE dump_target WITH (FORCE)",
+            "DROP ROLE dump_reader",
+        ] {
+            admin.batch_execute(statement).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL server at POSTGRESQL_TEST_URL"]
+    async fn partitioned_tables_fail_the_dump() {
+        let url = std::env::var("POSTGRESQL_TEST_URL").unwrap();
+        let (mut client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+
+        let transaction = client.transaction().await.unwrap();
+        transaction
+            .batch_execute(
+                "CREATE SCHEMA dump_test;
+                CREATE TABLE dump_test.plain (id int);
+                SET LOCAL search_path = '';",
+            )
+            .await
+            .unwrap();
+        check_supported(&transaction).await.unwrap();
+
+        transaction
+            .batch_execute(
+                "CREATE TABLE dump_test.measurements (at date, value int) PARTITION BY RANGE (at);
+                CREATE TABLE dump_test.measurements_2024 PARTITION OF dump_test.measurements
+                    FOR VALUES FROM ('2024-01-01') TO ('2025-01-01');",
+            )
+            .await
+            .unwrap();
+        let err = check_supported(&transaction).await.unwrap_err().to_string();
+        assert!(
+            err.contains("partitioned table dump_test.measurements"),
+            "{err}"
+        );
+        assert!(
+            err.contains("partition dump_test.measurements_2024"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use crate::{
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::postgresql::{
+        database::{Database, DatabaseRef},
+        is_missing,
+        sql::quote_ident,
+        user::{User, UserRef},
+    },
+};
+use kube::{
+    CustomResource,
+    api::{Api, ResourceExt},
+    core::object::HasSpec,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Privileges of a User on a Database it doesn't own
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(
+    kind = "Grant",
+    group = "postgresql.tjo.cloud",
+    version = "v1",
+    namespaced,
+    shortname = "grt",
+    status = "GrantStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"User", "type":"string", "jsonPath":".spec.userRef.name"}"#,
+    printcolumn = r#"{"name":"Database", "type":"string", "jsonPath":".spec.databaseRef.name"}"#,
+    printcolumn = r#"{"name":"Preset", "type":"string", "jsonPath":".spec.preset"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct GrantSpec {
+    pub server: String,
+    pub user_ref: UserRef,
+    pub database_ref: DatabaseRef,
+    pub preset: Preset,
+    /// Table privileges of the `Custom` preset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub privileges: Vec<TablePrivilege>,
+    /// Schemas the table privileges cover, defaults to `public`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schemas: Vec<String>,
+    /// Whether the privileges are revoked when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_policy: Option<DeletionPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, JsonSchema)]
+pub enum Preset {
+    /// Only connecting to the database.
+    #[default]
+    Connect,
+    /// Reading all tables and sequences.
+    ReadOnly,
+    /// Reading and writing all tables, and using sequences.
+    ReadWrite,
+    /// Exactly the table privileges listed in `privileges`.
+    Custom,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+pub enum TablePrivilege {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Truncate,
+    References,
+    Trigger,
+}
+
+impl TablePrivilege {
+    fn keyword(self) -> &'static str {
+        match self {
+            TablePrivilege::Select => "SELECT",
+            TablePrivilege::Insert => "INSERT",
+            TablePrivilege::Update => "UPDATE",
+            TablePrivilege::Delete => "DELETE",
+            TablePrivilege::Truncate => "TRUNCATE",
+            TablePrivilege::References => "REFERENCES",
+            TablePrivilege::Trigger => "TRIGGER",
+        }
+    }
+}
+
+/// Everything a grant gave, so exactly that can be revoked again.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedGrant {
+    pub user: String,
+    pub database: String,
+    /// Owner of the database, whose new objects get the default privileges.
+    pub owner: String,
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default)]
+    pub table_privileges: Vec<String>,
+    #[serde(default)]
+    pub sequence_privileges: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GrantStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Privileges given on the last successful reconcile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied: Option<AppliedGrant>,
+}
+
+impl ManagedStatus for GrantStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+/// Statements giving `applied`, in order.
+pub(super) fn grant_statements(applied: &AppliedGrant) -> Result<Vec<String>> {
+    let user = quote_ident(&applied.user)?;
+    let owner = quote_ident(&applied.owner)?;
+    let mut statements = vec![format!(
+        "GRANT CONNECT ON DATABASE {} TO {user}",
+        quote_ident(&applied.database)?
+    )];
+
+    for schema in &applied.schemas {
+        let schema = quote_ident(schema)?;
+        statements.push(format!("GRANT USAGE ON SCHEMA {schema} TO {user}"));
+        for (privileges, objects) in [
+            (&applied.table_privileges, "TABLES"),
+            (&applied.sequence_privileges, "SEQUENCES"),
+        ] {
+            if privileges.is_empty() {
+                continue;
+            }
+            let privileges = privileges.join(", ");
+            statements.push(format!(
+                "GRANT {privileges} ON ALL {objects} IN SCHEMA {schema} TO {user}"
+            ));
+            statements.push(format!(
+                "ALTER DEFAULT PRIVILEGES FOR ROLE {owner} IN SCHEMA {schema} GRANT {privileges} ON {objects} TO {user}"
+            ));
+        }
+    }
+    Ok(statements)
+}
+
+/// Statements taking back everything `applied` gave, in order.
+///
+/// Grouped by schema with CONNECT last on its own, so a schema that's gone only skips its own group.
+fn revoke_statements(applied: &AppliedGrant) -> Result<Vec<Vec<String>>> {
+    let user = quote_ident(&applied.user)?;
+    let owner = quote_ident(&applied.owner)?;
+    let mut groups = Vec::new();
+
+    for schema in &applied.schemas {
+        let schema = quote_ident(schema)?;
+        let mut statements = Vec::new();
+        for (privileges, objects) in [
+            (&applied.table_privileges, "TABLES"),
+            (&applied.sequence_privileges, "SEQUENCES"),
+        ] {
+            if privileges.is_empty() {
+                continue;
+            }
+            let privileges = privileges.join(", ");
+            statements.push(format!(
+                "ALTER DEFAULT PRIVILEGES FOR ROLE {owner} IN SCHEMA {schema} REVOKE {privileges} ON {objects} FROM {user}"
+            ));
+            statements.push(format!(
+                "REVOKE {privileges} ON ALL {objects} IN SCHEMA {schema} FROM {user}"
+            ));
+        }
+        statements.push(format!("REVOKE USAGE ON SCHEMA {schema} FROM {user}"));
+        groups.push(statements);
+    }
+    groups.push(vec![format!(
+        "REVOKE CONNECT ON DATABASE {} FROM {user}",
+        quote_ident(&applied.database)?
+    )]);
+    Ok(groups)
+}
+
+impl Grant {
+    /// Database the privileges are on, which has to be on the same server.
+    async fn database(&self, ctx: &Context) -> Result<Database> {
+        let databases: Api<Database> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let database = databases
+            .get(&self.spec().database_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if database.spec.server != self.spec().server {
+            return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
+        }
+        if !database
+            .status
+            .as_ref()
+            .is_some_and(|s| s.reconcile.created)
+        {
+            return Err(Error::NotReady(format!(
+                "database `{}`",
+                database.name_any()
+            )));
+        }
+        Ok(database)
+    }
+
+    /// User receiving the privileges, which has to live on the same server.
+    async fn user(&self, ctx: &Context) -> Result<User> {
+        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let user: User = users
+            .get(&self.spec().user_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if user.spec.server != self.spec().server {
+            return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
+        }
+
+        Ok(user)
+    }
+
+    /// Privileges the spec asks for, resolved against the live Database and User.
+    async fn wanted(&self, ctx: &Context) -> Result<AppliedGrant> {
+        let database = self.database(ctx).await?;
+        let user = self.user(ctx).await?;
+        let spec = self.spec();
+
+        let (tables, sequences): (Vec<&str>, Vec<&str>) = match spec.preset {
+            Preset::Connect => (vec![], vec![]),
+            Preset::ReadOnly => (vec!["SELECT"], vec!["SELECT"]),
+            Preset::ReadWrite => (
+                vec!["SELECT", "INSERT", "UPDATE", "DELETE"],
+                vec!["USAGE", "SELECT", "UPDATE"],
+            ),
+            Preset::Custom => (
+                spec.privileges.iter().map(|p| p.keyword()).collect(),
+                vec![],
+            ),
+        };
+        let schemas = match (spec.preset, spec.schemas.is_empty()) {
+            (Preset::Connect, _) => vec![],
+            (_, true) => vec!["public".to_string()],
+            (_, false) => spec.schemas.clone(),
+        };
+
+        Ok(AppliedGrant {
+            user: user.backend_name(&ctx.settings)?,
+            database: database.backend_name(&ctx.settings)?,
+            owner: database
+                .status
+                .map(|status| status.owner)
+                .unwrap_or_default(),
+            schemas,
+            table_privileges: tables.into_iter().map(String::from).collect(),
+            sequence_privileges: sequences.into_iter().map(String::from).collect(),
+        })
+    }
+
+    /// Run the groups of `revokes` and then `grants` in the database, all or nothing.
+    ///
+    /// Each group of revokes runs in a savepoint, one whose schema or role is gone is skipped.
+    async fn execute(
+        &self,
+        ctx: &Context,
+        database: &str,
+        revokes: Vec<Vec<String>>,
+        grants: Vec<String>,
+    ) -> Result<()> {
+        let mut client = ctx
+            .postgresql_databases
+            .get(&self.spec().server, database)?
+            .get()
+            .await?;
+        let mut tx = client.transaction().await?;
+        for group in revokes {
+            let savepoint = tx.savepoint("revoke").await?;
+            let result: Result<()> = async {
+                for statement in &group {
+                    savepoint.batch_execute(statement).await?;
+                }
+                Ok(())
+            }
+            .await;
+            match result {
+                Ok(()) => savepoint.commit().await?,
+                Err(err) if is_missing(&err) => savepoint.rollback().await?,
+                Err(err) => return Err(err),
+            }
+        }
+        for statement in grants {
+            tx.batch_execute(&statement).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+impl Managed for Grant {
+    type Observed = ();
+
+    fn backend(&self) -> Backend {
+        Backend::Postgresql(self.spec().server.clone())
+    }
+
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletion_policy
+    }
+
+    fn validate(&self) -> Result<()> {
+        let custom = self.spec().preset == Preset::Custom;
+        if custom == self.spec().privileges.is_empty() {
+            return Err(Error::IllegalDocument);
+        }
+        Ok(())
+    }
+
+    async fn observe(&self, _ctx: &Context) -> Result<Option<()>> {
+        // Privileges of others don't get in the way, so there's nothing to find before creating.
+        let created = self.status.as_ref().is_some_and(|s| s.reconcile.created);
+        Ok(created.then_some(()))
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut GrantStatus) -> Result<()> {
+        let wanted = self.wanted(ctx).await?;
+        self.execute(
+            ctx,
+            &wanted.database,
+            Vec::new(),
+            grant_statements(&wanted)?,
+        )
+        .await?;
+        status.applied = Some(wanted);
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        ctx: &Context,
+        _observed: (),
+        status: &mut GrantStatus,
+    ) -> Result<Vec<String>> {
+        let wanted = self.wanted(ctx).await?;
+        let mut changes = Vec::new();
+
+        // Granting is idempotent, so it's repeated to undo privileges revoked out-of-band.
+        let mut revokes = Vec::new();
+        if let Some(applied) = status
+            .applied
+            .as_ref()
+            .filter(|applied| **applied != wanted)
+        {
+            if applied.database == wanted.database {
+                revokes = revoke_statements(applied)?;
+            } else {
+                // Privileges inside the previous database can only be revoked from a connection to it.
+                let previous = revoke_statements(applied)?;
+                match self
+                    .execute(ctx, &applied.database, previous, Vec::new())
+                    .await
+                {
+                    Err(err) if is_missing(&err) => {}
+                    result => result?,
+                }
+            }
+            changes.push(format!(
+                "privileges of {} on {} replaced",
+                wanted.user, wanted.database
+            ));
+        }
+        self.execute(ctx, &wanted.database, revokes, grant_statements(&wanted)?)
+            .await?;
+
+        status.applied = Some(wanted);
+        Ok(changes)
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        let Some(applied) = self.status.as_ref().and_then(|s| s.applied.clone()) else {
+            return Ok(());
+        };
+        match self
+            .execute(
+                ctx,
+                &applied.database,
+                revoke_statements(&applied)?,
+                Vec::new(),
+            )
+            .await
+        {
+            // Whatever was granted went away with the database.
+            Err(err) if is_missing(&err) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Privileges have nowhere to carry a note, they are simply left in place.
+    async fn orphan(&self, _ctx: &Context, _note: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_only() -> AppliedGrant {
+        AppliedGrant {
+            user: "report".to_string(),
+            database: "app".to_string(),
+            owner: "app".to_string(),
+            schemas: vec!["public".to_string()],
+            table_privileges: vec!["SELECT".to_string()],
+            sequence_privileges: vec!["SELECT".to_string()],
+        }
+    }
+
+    #[test]
+    fn grant_statements_cover_existing_and_future_objects() {
+        assert_eq!(
+            grant_statements(&read_only()).unwrap(),
+            vec![
+                "GRANT CONNECT ON DATABASE \"app\" TO \"report\"",
+                "GRANT USAGE ON SCHEMA \"public\" TO \"report\"",
+                "GRANT SELECT ON ALL TABLES IN SCHEMA \"public\" TO \"report\"",
+                "ALTER DEFAULT PRIVILEGES FOR ROLE \"app\" IN SCHEMA \"public\" GRANT SELECT ON TABLES TO \"report\"",
+                "GRANT SELECT ON ALL SEQUENCES IN SCHEMA \"public\" TO \"report\"",
+                "ALTER DEFAULT PRIVILEGES FOR ROLE \"app\" IN SCHEMA \"public\" GRANT SELECT ON SEQUENCES TO \"report\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn revoke_statements_undo_grants_in_reverse() {
+        let connect = AppliedGrant {
+            schemas: vec![],
+            table_privileges: vec![],
+            sequence_privileges: vec![],
+            ..read_only()
+        };
+        assert_eq!(
+            revoke_statements(&connect).unwrap(),
+            vec![vec!["REVOKE CONNECT ON DATABASE \"app\" FROM \"report\""]]
+        );
+        let revoke = revoke_statements(&read_only()).unwrap();
+        assert_eq!(revoke.len(), 2);
+        assert_eq!(revoke[0].len(), 5);
+        assert_eq!(
+            revoke[0][0],
+            "ALTER DEFAULT PRIVILEGES FOR ROLE \"app\" IN SCHEMA \"public\" REVOKE SELECT ON TABLES FROM \"report\""
+        );
+        assert_eq!(
+            revoke[1],
+            vec!["REVOKE CONNECT ON DATABASE \"app\" FROM \"report\""]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{Error, Result, resources::postgresql::connect, settings::Postgresql};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant, timeout};
+use tokio_postgres::{Client, Row, types::ToSql};
+use tracing::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum HealthState {
+    /// No connection attempt has finished yet.
+    Connecting,
+    Healthy,
+    Unhealthy,
+}
+
+/// Connection health of a single server, as seen by the pool.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub state: HealthState,
+    pub last_error: Option<String>,
+    pub last_checked: Option<DateTime<Utc>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            state: HealthState::Connecting,
+            last_error: None,
+            last_checked: None,
+        }
+    }
+}
+
+struct Inner {
+    name: String,
+    settings: Postgresql,
+    idle: Mutex<Vec<Client>>,
+    permits: Arc<Semaphore>,
+    /// Limit shared with other pools of the same server.
+    shared_permits: Option<Arc<Semaphore>>,
+    health: RwLock<Health>,
+}
+
+/// Reconnecting pool of admin connections to one configured server.
+///
+/// Connections are opened lazily and dropped once they close, so a broken
+/// connection only fails the statements that were running on it.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+impl Pool {
+    pub fn new(name: String, settings: Postgresql) -> Pool {
+        Pool {
+            inner: Arc::new(Inner {
+                name,
+                permits: Arc::new(Semaphore::new(settings.pool_size.max(1))),
+                shared_permits: None,
+                settings,
+                idle: Mutex::new(Vec::new()),
+                health: RwLock::new(Health::default()),
+            }),
+        }
+    }
+
+    /// Pool for another database on the server, counting against `shared_permits` too.
+    fn for_database(
+        server: &str,
+        settings: &Postgresql,
+        database: &str,
+        shared_permits: Arc<Semaphore>,
+    ) -> Pool {
+        let settings = Postgresql {
+            database: database.to_string(),
+            pool_size: settings.database_pool_size,
+            ..settings.clone()
+        };
+        Pool {
+            inner: Arc::new(Inner {
+                name: format!("{server}/{database}"),
+                permits: Arc::new(Semaphore::new(settings.pool_size.max(1))),
+                shared_permits: Some(shared_permits),
+                settings,
+                idle: Mutex::new(Vec::new()),
+                health: RwLock::new(Health::default()),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn health(&self) -> Health {
+        self.inner.health.read().unwrap().clone()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health().state == HealthState::Healthy
+    }
+
+    fn set_health(&self, error: Option<String>) {
+        let mut health = self.inner.health.write().unwrap();
+        let state = match error {
+            None => HealthState::Healthy,
+            Some(_) => HealthState::Unhealthy,
+        };
+        if health.state != state {
+            match &error {
+                None => info!("Postgresql server name={} is healthy", self.inner.name),
+                Some(err) => warn!(
+                    "Postgresql server name={} is unhealthy: {err}",
+                    self.inner.name
+                ),
+            }
+        }
+        *health = Health {
+            state,
+            last_error: error,
+            last_checked: Some(Utc::now()),
+        };
+    }
+
+    fn statement_timeout(&self) -> Duration {
+        Duration::from_secs(self.inner.settings.statement_timeout_seconds)
+    }
+
+    /// Take a connection out of the pool, opening a new one when none is idle.
+    pub async fn get(&self) -> Result<PooledClient> {
+        let permits = self.permits().await?;
+        self.checkout(permits).await
+    }
+
+    /// Wait for a free slot in this pool, and in the server's shared limit.
+    async fn permits(&self) -> Result<Permits> {
+        let connect_timeout = Duration::from_secs(self.inner.settings.connect_timeout_seconds);
+        let permit = timeout(connect_timeout, self.inner.permits.clone().acquire_owned())
+            .await
+            .map_err(|_| Error::PostgresqlTimeout)?
+            .expect("pool semaphore is never closed");
+        let shared_permit = match &self.inner.shared_permits {
+            Some(permits) => Some(
+                timeout(connect_timeout, permits.clone().acquire_owned())
+                    .await
+                    .map_err(|_| Error::PostgresqlTimeout)?
+                    .expect("pool semaphore is never closed"),
+            ),
+            None => None,
+        };
+        Ok((permit, shared_permit))
+    }
+
+    async fn checkout(&self, permits: Permits) -> Result<PooledClient> {
+        let idle = {
+            let mut idle = self.inner.idle.lock().unwrap();
+            idle.retain(|client| !client.is_closed());
+            idle.pop()
+        };
+
+        let client = match idle {
+            Some(client) => client,
+            None => match connect(&self.inner.name, &self.inner.settings).await {
+                Ok(client) => {
+                    self.set_health(None);
+                    client
+                }
+                Err(err) => {
+                    self.set_health(Some(err.to_string()));
+                    return Err(err);
+                }
+            },
+        };
+
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+            reuse: true,
+            _permits: permits,
+        })
+    }
+
+    /// Take a connection for session level changes such as `SET ROLE`.
+    ///
+    /// It counts against the limits like any other, but is closed instead of
+    /// going back to the pool.
+    pub async fn session(&self) -> Result<PooledClient> {
+        let mut client = self.get().await?;
+        client.reuse = false;
+        Ok(client)
+    }
+
+    fn finish<T>(
+        &self,
+        client: &mut PooledClient,
+        result: Result<Result<T, tokio_postgres::Error>, tokio::time::error::Elapsed>,
+    ) -> Result<T> {
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(err)) => {
+                if client.is_closed() {
+                    self.set_health(Some(err.to_string()));
+                }
+                Err(Error::PostgresqlClientError(err))
+            }
+            Err(_) => {
+                // The statement may still be running, so the connection can't be reused.
+                client.discard();
+                Err(Error::PostgresqlTimeout)
+            }
+        }
+    }
+
+    pub async fn execute(&self, statement: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64> {
+        let mut client = self.get().await?;
+        let result = timeout(self.statement_timeout(), client.execute(statement, params)).await;
+        self.finish(&mut client, result)
+    }
+
+    pub async fn query(&self, statement: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>> {
+        let mut client = self.get().await?;
+        let result = timeout(self.statement_timeout(), client.query(statement, params)).await;
+        self.finish(&mut client, result)
+    }
+
+    pub async fn query_opt(
+        &self,
+        statement: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>> {
+        let mut client = self.get().await?;
+        let result = timeout(
+            self.statement_timeout(),
+            client.query_opt(statement, params),
+        )
+        .await;
+        self.finish(&mut client, result)
+    }
+
+    /// Check the server once, updating the health state.
+    ///
+    /// Waiting for a busy pool says nothing about the server, only connecting and querying count.
+    pub async fn check(&self) -> Result<()> {
+        let permits = self.permits().await?;
+        // A failed connection attempt already marked the server unhealthy.
+        let mut client = self.checkout(permits).await?;
+        let result = timeout(self.statement_timeout(), client.simple_query("SELECT 1")).await;
+        let result = self.finish(&mut client, result).map(|_| ());
+        match &result {
+            Ok(_) => self.set_health(None),
+            Err(err) => self.set_health(Some(err.to_string())),
+        }
+        result
+    }
+
+    /// Keep checking the server in the background for as long as the process runs.
+    pub async fn supervise(self) {
+        let interval = Duration::from_secs(self.inner.settings.health_check_interval_seconds);
+        loop {
+            let _ = self.check().await;
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Slots a connection holds, in its own pool and in the server's shared limit.
+type Permits = (OwnedSemaphorePermit, Option<OwnedSemaphorePermit>);
+
+/// Connection borrowed from a [`Pool`], returned to it on drop.
+pub struct PooledClient {
+    client: Option<Client>,
+    pool: Arc<Inner>,
+    reuse: bool,
+    _permits: Permits,
+}
+
+impl PooledClient {
+    /// Close the connection instead of returning it to the pool.
+    pub fn discard(&mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("discarded client is not used")
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("discarded client is not used")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take()
+            && self.reuse
+            && !client.is_closed()
+        {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+/// Admin pools for the databases on each server, opened when first used.
+///
+/// All pools of a server share `max_database_connections`, on top of their own
+/// `database_pool_size`. Pools nobody holds are closed once they sat idle for
+/// `database_idle_timeout_seconds`.
+pub struct DatabasePools {
+    servers: HashMap<String, (Postgresql, Arc<Semaphore>)>,
+    pools: Mutex<HashMap<(String, String), (Pool, Instant)>>,
+}
+
+impl DatabasePools {
+    pub fn new(servers: &HashMap<String, Postgresql>) -> DatabasePools {
+        DatabasePools {
+            servers: servers
+                .iter()
+                .map(|(name, settings)| {
+                    let permits = Semaphore::new(settings.max_database_connections.max(1));
+                    (name.clone(), (settings.clone(), Arc::new(permits)))
+                })
+                .collect(),
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Pool for `database` on `server`.
+    pub fn get(&self, server: &str, database: &str) -> Result<Pool> {
+        let (settings, permits) = self
+            .servers
+            .get(server)
+            .ok_or(Error::PostgresqlUnknownServer)?;
+        let mut pools = self.pools.lock().unwrap();
+        let (pool, last_used) = pools
+            .entry((server.to_string(), database.to_string()))
+            .or_insert_with(|| {
+                (
+                    Pool::for_database(server, settings, database, permits.clone()),
+                    Instant::now(),
+                )
+            });
+        *last_used = Instant::now();
+        Ok(pool.clone())
+    }
+
+    /// Forget the pool for `database`, its connections close in the background.
+    pub fn close(&self, server: &str, database: &str) {
+        self.pools
+            .lock()
+            .unwrap()
+            .remove(&(server.to_string(), database.to_string()));
+    }
+
+    /// Close the pools nobody holds that weren't used within the idle timeout.
+    pub fn evict_idle(&self) {
+        self.pools
+            .lock()
+            .unwrap()
+            .retain(|(server, _), (pool, last_used)| {
+                let idle_timeout = self
+                    .servers
+                    .get(server)
+                    .map(|(settings, _)| settings.database_idle_timeout_seconds)
+                    .unwrap_or_default();
+                // Checked out clients keep a reference to the pool too.
+                Arc::strong_count(&pool.inner) > 1
+                    || last_used.elapsed() < Duration::from_secs(idle_timeout)
+            });
+    }
+
+    /// Keep evicting idle pools for as long as the process runs.
+    pub async fn supervise(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            self.evict_idle();
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{
+    Context, Error, Result, S3Client,
+    reconciler::{
+        Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus, report_progress,
+    },
+    resources::{
+        postgresql::{
+            connect,
+            database::{Database, DatabaseRef},
+            dump::{USER_SCHEMA, not_extension_member},
+            restored, restored_comment, sql,
+            statements::{Item, Splitter},
+            user::User,
+        },
+        s3::bucket::{Bucket, BucketRef},
+    },
+    settings::Postgresql,
+};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::SinkExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::{
+    CustomResource, Resource,
+    api::{Api, ResourceExt},
+    core::object::HasSpec,
+    runtime::events::{Event, EventType},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use tokio::time::{Duration, Instant};
+use tokio_postgres::{Client, CopyInSink, Transaction};
+
+/// How often the Progressing condition is updated while a dump is replayed.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Replays a dump stored in a Bucket into a Database
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(
+    kind = "Restore",
+    group = "postgresql.tjo.cloud",
+    version = "v1",
+    namespaced,
+    shortname = "rst",
+    status = "RestoreStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Database", "type":"string", "jsonPath":".spec.databaseRef.name"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreSpec {
+    pub bucket_ref: BucketRef,
+    /// Key of the dump in the bucket, as recorded on the Backup's status.
+    pub object_key: String,
+    pub server: String,
+    pub database_ref: DatabaseRef,
+    /// Drop the schemas and objects the database owner has in the database first, otherwise it has to be empty.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Statements replayed, each COPY counts as one.
+    #[serde(default)]
+    pub statements: u64,
+    /// Rows loaded with COPY.
+    #[serde(default)]
+    pub rows: u64,
+    /// Bytes of the dump downloaded.
+    #[serde(default)]
+    pub size_bytes: u64,
+    #[serde(default)]
+    pub duration_seconds: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<Time>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<Time>,
+}
+
+impl ManagedStatus for RestoreStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+impl Restore {
+    /// Database the dump is restored into, which has to be on the same server.
+    async fn database(&self, ctx: &Context) -> Result<Database> {
+        let databases: Api<Database> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let database = databases
+            .get(&self.spec().database_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if database.spec.server != self.spec().server {
+            return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
+        }
+        if !database
+            .status
+            .as_ref()
+            .is_some_and(|s| s.reconcile.created)
+        {
+            return Err(Error::NotReady(format!(
+                "database `{}`",
+                database.name_any()
+            )));
+        }
+        Ok(database)
+    }
+
+    async fn bucket(&self, ctx: &Context) -> Result<Bucket> {
+        let buckets: Api<Bucket> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let bucket = buckets
+            .get(&self.spec().bucket_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if bucket.get_id().is_empty() {
+            return Err(Error::NotReady(format!("bucket `{}`", bucket.name_any())));
+        }
+        Ok(bucket)
+    }
+
+    /// Connection to the database as its owner, logged in with the owner's own password.
+    ///
+    /// The dump comes from a bucket the namespace can write to, so it may hold any statement.
+    /// Restored objects belong to the owner, as if they had created them, and the dump
+    /// can't do anything the owner couldn't.
+    async fn owner_client(
+        &self,
+        ctx: &Context,
+        database: &Database,
+        database_name: &str,
+    ) -> Result<(Client, String)> {
+        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let user = users
+            .get(&database.spec.ownerRef.name)
+            .await
+            .map_err(Error::KubeError)?;
+        let role = user.backend_name(&ctx.settings)?;
+        let password = user
+            .login_secret(ctx)
+            .await?
+            .password(ctx)
+            .await?
+            .ok_or_else(|| Error::NotReady(format!("secret of user `{}`", user.name_any())))?;
+
+        let server = &database.spec.server;
+        let settings = Postgresql {
+            user: role.clone(),
+            password,
+            database: database_name.to_string(),
+            ..ctx
+                .settings
+                .postgresql
+                .get(server)
+                .ok_or(Error::PostgresqlUnknownServer)?
+                .clone()
+        };
+        let client = connect(&format!("{server}/{database_name}"), &settings).await?;
+        Ok((client, role))
+    }
+
+    async fn event(
+        &self,
+        ctx: &Context,
+        type_: EventType,
+        reason: &str,
+        note: String,
+    ) -> Result<()> {
+        ctx.recorder
+            .publish(
+                &Event {
+                    type_,
+                    reason: reason.into(),
+                    note: Some(note),
+                    action: "Restoring".into(),
+                    secondary: None,
+                },
+                &self.object_ref(&()),
+            )
+            .await
+            .map_err(Error::KubeError)
+    }
+
+    async fn restore(&self, ctx: &Context, status: &mut RestoreStatus) -> Result<()> {
+        let s3_client = ctx.s3_client.as_ref().ok_or(Error::S3NotConfigured)?;
+        let database = self.database(ctx).await?;
+        let bucket = self.bucket(ctx).await?;
+        let database_name = database.backend_name(&ctx.settings)?;
+        let bucket_name = bucket.backend_name(&ctx.settings)?;
+        let replay = self.replay_dump(
+            ctx,
+            s3_client,
+            &database,
+            &database_name,
+            &bucket_name,
+            status,
+        );
+        s3_client
+            .with_bucket(&ctx.garage_client, &bucket.get_id(), replay)
+            .await
+    }
+
+    /// Download the dump and replay it into the database.
+    async fn replay_dump(
+        &self,
+        ctx: &Context,
+        s3_client: &S3Client,
+        database: &Database,
+        database_name: &str,
+        bucket_name: &str,
+        status: &mut RestoreStatus,
+    ) -> Result<()> {
+        let started_at = Utc::now();
+        *status = RestoreStatus {
+            reconcile: status.reconcile.clone(),
+            started_at: Some(Time(started_at)),
+            ..Default::default()
+        };
+
+        let uid = self.uid().unwrap_or_default();
+        let (mut client, owner) = self.owner_client(ctx, database, database_name).await?;
+        client.batch_execute("SET statement_timeout = 0").await?;
+
+        // Everything happens in one transaction, a failed restore leaves the database as it was.
+        let tx = client.transaction().await?;
+        let comment: String = tx
+            .query_one(
+                "SELECT coalesce(shobj_description(oid, 'pg_database'), '') FROM pg_database
+                WHERE datname = current_database()",
+                &[],
+            )
+            .await?
+            .get(0);
+        // Committed before, only recording it on the status failed.
+        if restored(&comment, &uid) {
+            status.completed_at = Some(Time(Utc::now()));
+            return Ok(());
+        }
+
+        let mut download = s3_client
+            .download(bucket_name, &self.spec().object_key)
+            .await?;
+        if self.spec().overwrite {
+            self.event(
+                ctx,
+                EventType::Warning,
+                "Overwriting",
+                format!(
+                    "Dropping the schemas and objects `{owner}` owns in database `{}`",
+                    database_name
+                ),
+            )
+            .await?;
+            // Objects dropped by an earlier cascade are skipped with IF EXISTS.
+            for row in tx.query(&drop_owned_objects(), &[]).await? {
+                tx.batch_execute(row.get(0)).await?;
+            }
+        } else {
+            let row = tx
+                .query_one(
+                    &format!(
+                        "SELECT count(*) FROM pg_class c
+                        JOIN pg_namespace n ON n.oid = c.relnamespace
+                        WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S', 'f') AND {USER_SCHEMA} AND {}",
+                        not_extension_member("pg_class", "c.oid")
+                    ),
+                    &[],
+                )
+                .await?;
+            if row.get::<_, i64>(0) > 0 {
+                return Err(Error::RestoreTargetNotEmpty(database_name.to_string()));
+            }
+        }
+
+        let total = download
+            .size()
+            .map(|size| format!(" of {}", size / 1024 / 1024))
+            .unwrap_or_default();
+        let mut splitter = Splitter::default();
+        let mut copy: Option<Pin<Box<CopyInSink<Bytes>>>> = None;
+        let mut reported = Instant::now();
+        let mut eof = false;
+        loop {
+            while let Some(item) = splitter.next_item(eof)? {
+                replay(&tx, item, &mut copy, status).await?;
+            }
+            if eof {
+                break;
+            }
+            match download.chunk().await? {
+                Some(chunk) => {
+                    status.size_bytes += chunk.len() as u64;
+                    splitter.push(&chunk);
+                }
+                None => eof = true,
+            }
+
+            if reported.elapsed() >= PROGRESS_INTERVAL {
+                let message = format!(
+                    "Restoring `{}`: {} statements and {} rows replayed, {}{total} MiB downloaded",
+                    self.spec().object_key,
+                    status.statements,
+                    status.rows,
+                    status.size_bytes / 1024 / 1024
+                );
+                report_progress(self, ctx, status, message).await?;
+                reported = Instant::now();
+            }
+        }
+        // Recorded with the restore itself, so a retry can't run it a second time.
+        tx.batch_execute(&sql::comment_on_database(
+            database_name,
+            &restored_comment(&comment, &uid),
+        )?)
+        .await?;
+        tx.commit().await?;
+
+        let completed_at = Utc::now();
+        status.duration_seconds = (completed_at - started_at).as_seconds_f64();
+        status.completed_at = Some(Time(completed_at));
+        Ok(())
+    }
+}
+
+/// Statements dropping the current user's schemas and objects in the database.
+///
+/// Unlike `DROP OWNED`, privileges the user holds, on the database or shared objects, stay.
+/// Sequences go after tables, those owned by a column are dropped along with it, and
+/// functions a range type created go with the type.
+fn drop_owned_objects() -> String {
+    let owned = |owner: &str| format!("pg_get_userbyid({owner}) = current_user");
+    let schemas = format!(
+        "SELECT 0, format('DROP SCHEMA IF EXISTS %I CASCADE', n.nspname)
+        FROM pg_namespace n
+        WHERE {USER_SCHEMA} AND n.nspname <> 'public' AND {} AND {}",
+        owned("n.nspowner"),
+        not_extension_member("pg_namespace", "n.oid")
+    );
+    let relations = format!(
+        "SELECT CASE c.relkind WHEN 'S' THEN 2 ELSE 1 END,
+            format('DROP %s IF EXISTS %I.%I CASCADE',
+            CASE c.relkind WHEN 'v' THEN 'VIEW' WHEN 'm' THEN 'MATERIALIZED VIEW'
+                WHEN 'S' THEN 'SEQUENCE' WHEN 'f' THEN 'FOREIGN TABLE' ELSE 'TABLE' END,
+            n.nspname, c.relname)
+        FROM pg_class c
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE c.relkind IN ('r', 'p', 'v', 'm', 'S', 'f') AND {USER_SCHEMA} AND {} AND {}",
+        owned("c.relowner"),
+        not_extension_member("pg_class", "c.oid")
+    );
+    let routines = format!(
+        "SELECT 3, format('DROP ROUTINE IF EXISTS %s CASCADE', p.oid::regprocedure)
+        FROM pg_proc p
+        JOIN pg_namespace n ON n.oid = p.pronamespace
+        WHERE {USER_SCHEMA} AND {} AND {}
+        AND NOT EXISTS (SELECT 1 FROM pg_depend d WHERE d.classid = 'pg_proc'::regclass AND d.objid = p.oid AND d.deptype = 'i')",
+        owned("p.proowner"),
+        not_extension_member("pg_proc", "p.oid")
+    );
+    let types = format!(
+        "SELECT 4, format('DROP %s IF EXISTS %I.%I CASCADE',
+            CASE t.typtype WHEN 'd' THEN 'DOMAIN' ELSE 'TYPE' END, n.nspname, t.typname)
+        FROM pg_type t
+        JOIN pg_namespace n ON n.oid = t.typnamespace
+        LEFT JOIN pg_class c ON c.oid = t.typrelid
+        WHERE (t.typtype IN ('e', 'd', 'r') OR c.relkind = 'c') AND {USER_SCHEMA} AND {} AND {}",
+        owned("t.typowner"),
+        not_extension_member("pg_type", "t.oid")
+    );
+    format!(
+        "SELECT statement FROM ({schemas} UNION ALL {relations} UNION ALL {routines} UNION ALL {types}) AS s(step, statement)
+        ORDER BY step"
+    )
+}
+
+/// Run one item of the dump, COPY data goes to the sink opened by the preceding `Copy`.
+async fn replay(
+    tx: &Transaction<'_>,
+    item: Item,
+    copy: &mut Option<Pin<Box<CopyInSink<Bytes>>>>,
+    status: &mut RestoreStatus,
+) -> Result<()> {
+    let no_copy = || Error::IllegalDump("COPY data without a COPY statement".to_string());
+    match item {
+        Item::Statement(statement) => {
+            tx.batch_execute(&statement).await?;
+            status.statements += 1;
+        }
+        Item::Copy(statement) => {
+            *copy = Some(Box::pin(tx.copy_in(&statement).await?));
+        }
+        Item::CopyData(data) => {
+            copy.as_mut()
+                .ok_or_else(no_copy)?
+                .send(Bytes::from(data))
+                .await?;
+        }
+        Item::CopyDone => {
+            let mut sink = copy.take().ok_or_else(no_copy)?;
+            status.rows += sink.as_mut().finish().await?;
+            status.statements += 1;
+        }
+    }
+    Ok(())
+}
+
+impl Managed for Restore {
+    type Observed = ();
+
+    /// The dump is read from Garage, but the restore runs on the database's server.
+    fn backend(&self) -> Backend {
+        Backend::Postgresql(self.spec().server.clone())
+    }
+
+    /// Restored data belongs to the Database, deleting the Restore leaves it alone.
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        Some(DeletionPolicy::Delete)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.spec().object_key.is_empty() {
+            return Err(Error::IllegalDocument);
+        }
+        Ok(())
+    }
+
+    async fn observe(&self, _ctx: &Context) -> Result<Option<()>> {
+        // A restore runs once, it's done when the completion is recorded.
+        let completed = self
+            .status
+            .as_ref()
+            .is_some_and(|s| s.completed_at.is_some());
+        Ok(completed.then_some(()))
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut RestoreStatus) -> Result<()> {
+        if let Err(err) = self.restore(ctx, status).await {
+            self.event(
+                ctx,
+                EventType::Warning,
+                "RestoreFailed",
+                format!("Restoring `{}` failed: {err}", self.spec().object_key),
+            )
+            .await?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        _ctx: &Context,
+        _observed: (),
+        _status: &mut RestoreStatus,
+    ) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    async fn delete(&self, _ctx: &Context) -> Result<()> {
+        Ok(())
+    }
+
+    async fn orphan(&self, _ctx: &Context, _note: &str) -> Result<()> {
+        Ok(())
+    }
+}
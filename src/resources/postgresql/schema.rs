@@ -0,0 +1,352 @@
+use crate::{
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::postgresql::{
+        database::{Database, DatabaseRef},
+        is_missing, ownership_comment, ownership_marker, sql,
+        user::{User, UserRef},
+    },
+    settings::Settings,
+};
+use kube::{
+    CustomResource,
+    api::{Api, ResourceExt},
+    core::object::HasSpec,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Schema inside a Database on the postgresql.tjo.cloud database platform
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(Default))]
+#[kube(
+    kind = "Schema",
+    group = "postgresql.tjo.cloud",
+    version = "v1",
+    namespaced,
+    shortname = "sch",
+    status = "SchemaStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Database", "type":"string", "jsonPath":".spec.databaseRef.name"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaSpec {
+    #[schemars(length(min = 1, max = 63), pattern(r"^[a-z0-9._]+$"))]
+    pub name: String,
+    pub server: String,
+    pub database_ref: DatabaseRef,
+    pub owner_ref: UserRef,
+    /// Set the owner's `search_path` in the database to this schema, followed by `public`.
+    #[serde(default)]
+    pub search_path: bool,
+    /// What happens to the schema and everything in it when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_policy: Option<DeletionPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Database the schema was created in, it's still needed once the Database resource is gone.
+    #[serde(default)]
+    pub database: String,
+    /// Owner applied on the last successful reconcile.
+    #[serde(default)]
+    pub owner: String,
+    /// Whether the owner's `search_path` was set on the last successful reconcile.
+    #[serde(default)]
+    pub search_path: bool,
+    /// Name the schema was created under, editing the spec doesn't point the resource at another schema.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_name: Option<String>,
+}
+
+impl ManagedStatus for SchemaStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+/// Live state of a schema, as read from `pg_namespace` and `pg_db_role_setting`.
+pub struct ObservedSchema {
+    pub owner: String,
+    /// The owner's `search_path` in the database starts with this schema.
+    pub search_path: bool,
+}
+
+impl Schema {
+    /// Name of the schema in the database, the spec only applies until it's recorded on the status.
+    fn backend_name(&self) -> String {
+        self.status
+            .as_ref()
+            .and_then(|status| status.backend_name.clone())
+            .unwrap_or_else(|| self.spec().name.clone())
+    }
+
+    /// Database the schema was created in, looked up through the Database until it's recorded.
+    async fn database_name(&self, ctx: &Context) -> Result<String> {
+        match self.status.as_ref().map(|status| &status.database) {
+            Some(database) if !database.is_empty() => Ok(database.clone()),
+            _ => self.database(ctx).await?.backend_name(&ctx.settings),
+        }
+    }
+
+    /// Database the schema lives in, which has to be on the same server.
+    async fn database(&self, ctx: &Context) -> Result<Database> {
+        let databases: Api<Database> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let database = databases
+            .get(&self.spec().database_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if database.spec.server != self.spec().server {
+            return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
+        }
+        if !database
+            .status
+            .as_ref()
+            .is_some_and(|s| s.reconcile.created)
+        {
+            return Err(Error::NotReady(format!(
+                "database `{}`",
+                database.name_any()
+            )));
+        }
+        Ok(database)
+    }
+
+    /// Owning user, which has to live on the same server.
+    async fn owner(&self, ctx: &Context) -> Result<User> {
+        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        let user: User = users
+            .get(&self.spec().owner_ref.name)
+            .await
+            .map_err(Error::KubeError)?;
+
+        if user.spec.server != self.spec().server {
+            return Err(Error::PostgresqlUserAndDatabaseServerNotMatching);
+        }
+
+        Ok(user)
+    }
+
+    /// Set or reset the owner's `search_path`, as the spec asks for.
+    async fn apply_search_path(&self, ctx: &Context, owner: &str, database: &str) -> Result<()> {
+        let statement = if self.spec().search_path {
+            sql::set_role_search_path(owner, database, &self.backend_name())?
+        } else {
+            sql::reset_role_search_path(owner, database)?
+        };
+        ctx.postgresql_clients[&self.spec().server]
+            .execute(&statement, &[])
+            .await?;
+        Ok(())
+    }
+}
+
+impl Managed for Schema {
+    type Observed = ObservedSchema;
+
+    fn backend(&self) -> Backend {
+        Backend::Postgresql(self.spec().server.clone())
+    }
+
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletion_policy
+    }
+
+    fn validate(&self) -> Result<()> {
+        sql::check_name(&self.spec().name)
+    }
+
+    /// Schemas are only unique within their database.
+    fn identity(&self, _settings: &Settings, status: &SchemaStatus) -> Option<String> {
+        (!status.database.is_empty()).then(|| {
+            let name = status.backend_name.as_ref().unwrap_or(&self.spec().name);
+            format!("{}/{}/{name}", self.spec().server, status.database)
+        })
+    }
+
+    async fn marker(&self, ctx: &Context) -> Result<Option<String>> {
+        let database = self.database_name(ctx).await?;
+        let row = ctx
+            .postgresql_databases
+            .get(&self.spec().server, &database)?
+            .query_opt(
+                "SELECT obj_description(oid, 'pg_namespace') FROM pg_namespace WHERE nspname = $1",
+                &[&self.backend_name()],
+            )
+            .await?;
+        let comment: Option<String> = row.and_then(|row| row.get(0));
+        Ok(comment
+            .as_deref()
+            .and_then(ownership_marker)
+            .map(str::to_string))
+    }
+
+    async fn mark(&self, ctx: &Context, uid: &str) -> Result<()> {
+        let database = self.database_name(ctx).await?;
+        ctx.postgresql_databases
+            .get(&self.spec().server, &database)?
+            .execute(
+                &sql::comment_on_schema(&self.backend_name(), &ownership_comment(uid))?,
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn observe(&self, ctx: &Context) -> Result<Option<ObservedSchema>> {
+        let database = self.database_name(ctx).await?;
+        let row = ctx
+            .postgresql_databases
+            .get(&self.spec().server, &database)?
+            .query_opt(
+                "SELECT pg_get_userbyid(n.nspowner), EXISTS (
+                    SELECT 1 FROM pg_db_role_setting s
+                    JOIN pg_database d ON d.oid = s.setdatabase
+                    WHERE d.datname = current_database() AND s.setrole = n.nspowner
+                    AND format('search_path=%s, public', quote_ident(n.nspname)) = ANY (s.setconfig)
+                )
+                FROM pg_namespace n WHERE n.nspname = $1",
+                &[&self.backend_name()],
+            )
+            .await;
+        let row = match row {
+            // Dropping the database took the schema along.
+            Err(err) if is_missing(&err) => return Ok(None),
+            row => row?,
+        };
+
+        Ok(row.map(|row| ObservedSchema {
+            owner: row.get(0),
+            search_path: row.get(1),
+        }))
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut SchemaStatus) -> Result<()> {
+        let name = self.spec().name.clone();
+        let database = self.database(ctx).await?.backend_name(&ctx.settings)?;
+        let owner = self.owner(ctx).await?.backend_name(&ctx.settings)?;
+
+        // Marked together with the schema, so a later pass recognises it even when this one fails.
+        let mut client = ctx
+            .postgresql_databases
+            .get(&self.spec().server, &database)?
+            .get()
+            .await?;
+        let tx = client.transaction().await?;
+        tx.batch_execute(&sql::create_schema(&name, &owner)?)
+            .await?;
+        tx.batch_execute(&sql::comment_on_schema(
+            &name,
+            &ownership_comment(&self.uid().unwrap_or_default()),
+        )?)
+        .await?;
+        tx.commit().await?;
+        status.reconcile.marked = true;
+        status.backend_name = Some(name);
+
+        if self.spec().search_path {
+            self.apply_search_path(ctx, &owner, &database).await?;
+        }
+
+        status.database = database;
+        status.owner = owner;
+        status.search_path = self.spec().search_path;
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        ctx: &Context,
+        observed: ObservedSchema,
+        status: &mut SchemaStatus,
+    ) -> Result<Vec<String>> {
+        let name = self.backend_name();
+        let database = self.database_name(ctx).await?;
+        let owner = self.owner(ctx).await?.backend_name(&ctx.settings)?;
+        let mut changes = Vec::new();
+
+        if observed.owner != owner {
+            ctx.postgresql_databases
+                .get(&self.spec().server, &database)?
+                .execute(&sql::alter_schema_owner(&name, &owner)?, &[])
+                .await?;
+            // The previous owner's search_path would point at a schema it no longer owns.
+            if status.search_path && !status.owner.is_empty() && status.owner != owner {
+                ctx.postgresql_clients[&self.spec().server]
+                    .execute(&sql::reset_role_search_path(&status.owner, &database)?, &[])
+                    .await?;
+            }
+            changes.push(format!("owner {} -> {}", observed.owner, owner));
+        }
+
+        // Only a search_path this resource set is ever reset.
+        let reset = status.search_path && !self.spec().search_path;
+        if (self.spec().search_path && !observed.search_path) || reset {
+            self.apply_search_path(ctx, &owner, &database).await?;
+            changes.push(format!(
+                "search_path {}",
+                if reset { "reset" } else { "set" }
+            ));
+        }
+
+        status.backend_name = Some(name);
+        status.database = database;
+        status.owner = owner;
+        status.search_path = self.spec().search_path;
+        Ok(changes)
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        let status = self.status.clone().unwrap_or_default();
+        if status.database.is_empty() {
+            return Ok(());
+        }
+
+        let result = async {
+            if status.search_path {
+                ctx.postgresql_clients[&self.spec().server]
+                    .execute(
+                        &sql::reset_role_search_path(&status.owner, &status.database)?,
+                        &[],
+                    )
+                    .await?;
+            }
+            ctx.postgresql_databases
+                .get(&self.spec().server, &status.database)?
+                .execute(&sql::drop_schema(&self.backend_name())?, &[])
+                .await?;
+            Ok(())
+        }
+        .await;
+        match result {
+            // Dropping the database or owner took the schema along.
+            Err(err) if is_missing(&err) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn orphan(&self, ctx: &Context, note: &str) -> Result<()> {
+        let status = self.status.clone().unwrap_or_default();
+        if status.database.is_empty() {
+            return Ok(());
+        }
+
+        ctx.postgresql_databases
+            .get(&self.spec().server, &status.database)?
+            .execute(&sql::comment_on_schema(&self.backend_name(), note)?, &[])
+            .await?;
+        Ok(())
+    }
+}
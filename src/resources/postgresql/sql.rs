@@ -0,0 +1,289 @@
+//! Building blocks for SQL statements sent to the managed servers.
+//!
+//! Utility statements such as `CREATE DATABASE` or `ALTER ROLE` can't take bind
+//! parameters, so every name and literal spliced into them has to go through
+//! [`quote_ident`] or [`quote_literal`].
+use crate::{Error, Result};
+
+/// Longest identifier PostgreSQL keeps without truncating (NAMEDATALEN - 1).
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Quote an identifier the way `quote_ident()` does, but always.
+///
+/// Identifiers that PostgreSQL would silently truncate are rejected, so two
+/// long names can never end up pointing at the same object.
+pub fn quote_ident(ident: &str) -> Result<String> {
+    if ident.is_empty() || ident.len() > MAX_IDENTIFIER_LENGTH || ident.contains('\0') {
+        return Err(Error::PostgresqlIllegalIdentifier(ident.to_string()));
+    }
+    Ok(format!("\"{}\"", ident.replace('"', "\"\"")))
+}
+
+/// Reject resource names that aren't lowercase letters, digits, `.` and `_`.
+///
+/// Before identifiers were quoted PostgreSQL folded names to lowercase, so any
+/// other name would now point at a different object than the one created for it.
+pub fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_'));
+    if !valid {
+        return Err(Error::PostgresqlIllegalIdentifier(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Quote a string literal the way `quote_literal()` does.
+///
+/// Backslashes switch to the escape string syntax, so the result is safe no
+/// matter how `standard_conforming_strings` is set.
+pub fn quote_literal(literal: &str) -> Result<String> {
+    if literal.contains('\0') {
+        return Err(Error::PostgresqlIllegalLiteral);
+    }
+    let quoted = literal.replace('\'', "''");
+    if quoted.contains('\\') {
+        Ok(format!("E'{}'", quoted.replace('\\', "\\\\")))
+    } else {
+        Ok(format!("'{quoted}'"))
+    }
+}
+
+pub fn create_database(name: &str, owner: &str, connection_limit: i32) -> Result<String> {
+    Ok(format!(
+        "CREATE DATABASE {} WITH OWNER {} CONNECTION LIMIT {connection_limit}",
+        quote_ident(name)?,
+        quote_ident(owner)?,
+    ))
+}
+
+pub fn alter_database_connection_limit(name: &str, connection_limit: i32) -> Result<String> {
+    Ok(format!(
+        "ALTER DATABASE {} CONNECTION LIMIT {connection_limit}",
+        quote_ident(name)?
+    ))
+}
+
+pub fn alter_database_owner(name: &str, owner: &str) -> Result<String> {
+    Ok(format!(
+        "ALTER DATABASE {} OWNER TO {}",
+        quote_ident(name)?,
+        quote_ident(owner)?
+    ))
+}
+
+pub fn drop_database(name: &str) -> Result<String> {
+    Ok(format!("DROP DATABASE {}", quote_ident(name)?))
+}
+
+/// End the sessions `application` has open on `database`, leaving everyone else's alone.
+pub fn terminate_backends(database: &str, application: &str) -> Result<String> {
+    Ok(format!(
+        "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+         WHERE datname = {} AND application_name = {} AND pid <> pg_backend_pid()",
+        quote_literal(database)?,
+        quote_literal(application)?
+    ))
+}
+
+pub fn comment_on_database(name: &str, comment: &str) -> Result<String> {
+    Ok(format!(
+        "COMMENT ON DATABASE {} IS {}",
+        quote_ident(name)?,
+        quote_literal(comment)?
+    ))
+}
+
+pub fn create_extension(name: &str) -> Result<String> {
+    Ok(format!(
+        "CREATE EXTENSION IF NOT EXISTS {}",
+        quote_ident(name)?
+    ))
+}
+
+pub fn drop_extension(name: &str) -> Result<String> {
+    Ok(format!("DROP EXTENSION IF EXISTS {}", quote_ident(name)?))
+}
+
+pub fn create_schema(name: &str, owner: &str) -> Result<String> {
+    Ok(format!(
+        "CREATE SCHEMA {} AUTHORIZATION {}",
+        quote_ident(name)?,
+        quote_ident(owner)?
+    ))
+}
+
+pub fn alter_schema_owner(name: &str, owner: &str) -> Result<String> {
+    Ok(format!(
+        "ALTER SCHEMA {} OWNER TO {}",
+        quote_ident(name)?,
+        quote_ident(owner)?
+    ))
+}
+
+pub fn drop_schema(name: &str) -> Result<String> {
+    Ok(format!(
+        "DROP SCHEMA IF EXISTS {} CASCADE",
+        quote_ident(name)?
+    ))
+}
+
+pub fn comment_on_schema(name: &str, comment: &str) -> Result<String> {
+    Ok(format!(
+        "COMMENT ON SCHEMA {} IS {}",
+        quote_ident(name)?,
+        quote_literal(comment)?
+    ))
+}
+
+/// Make `schema` the first place `role` looks for objects inside `database`.
+pub fn set_role_search_path(role: &str, database: &str, schema: &str) -> Result<String> {
+    Ok(format!(
+        "ALTER ROLE {} IN DATABASE {} SET search_path = {}, public",
+        quote_ident(role)?,
+        quote_ident(database)?,
+        quote_ident(schema)?
+    ))
+}
+
+pub fn reset_role_search_path(role: &str, database: &str) -> Result<String> {
+    Ok(format!(
+        "ALTER ROLE {} IN DATABASE {} RESET search_path",
+        quote_ident(role)?,
+        quote_ident(database)?
+    ))
+}
+
+pub fn create_user(name: &str, password: &str, connection_limit: i32) -> Result<String> {
+    Ok(format!(
+        "CREATE USER {} WITH PASSWORD {} CONNECTION LIMIT {connection_limit}",
+        quote_ident(name)?,
+        quote_literal(password)?,
+    ))
+}
+
+pub fn alter_role_connection_limit(name: &str, connection_limit: i32) -> Result<String> {
+    Ok(format!(
+        "ALTER ROLE {} CONNECTION LIMIT {connection_limit}",
+        quote_ident(name)?
+    ))
+}
+
+pub fn alter_role_login(name: &str) -> Result<String> {
+    Ok(format!("ALTER ROLE {} LOGIN", quote_ident(name)?))
+}
+
+pub fn alter_role_password(name: &str, password: &str) -> Result<String> {
+    Ok(format!(
+        "ALTER ROLE {} WITH PASSWORD {}",
+        quote_ident(name)?,
+        quote_literal(password)?
+    ))
+}
+
+pub fn comment_on_role(name: &str, comment: &str) -> Result<String> {
+    Ok(format!(
+        "COMMENT ON ROLE {} IS {}",
+        quote_ident(name)?,
+        quote_literal(comment)?
+    ))
+}
+
+pub fn drop_user(name: &str) -> Result<String> {
+    Ok(format!("DROP USER {}", quote_ident(name)?))
+}
+
+pub fn drop_user_if_exists(name: &str) -> Result<String> {
+    Ok(format!("DROP USER IF EXISTS {}", quote_ident(name)?))
+}
+
+/// Drop what `role` owns in the current database and revoke its privileges there.
+pub fn drop_owned_by(role: &str) -> Result<String> {
+    Ok(format!("DROP OWNED BY {}", quote_ident(role)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quote_ident_wraps_plain_names() {
+        assert_eq!(quote_ident("example").unwrap(), "\"example\"");
+        assert_eq!(quote_ident("Example.Name").unwrap(), "\"Example.Name\"");
+    }
+
+    #[test]
+    fn quote_ident_doubles_embedded_quotes() {
+        assert_eq!(
+            quote_ident("a\"; DROP DATABASE postgres; --").unwrap(),
+            "\"a\"\"; DROP DATABASE postgres; --\""
+        );
+        assert_eq!(quote_ident("\"").unwrap(), "\"\"\"\"");
+    }
+
+    #[test]
+    fn quote_ident_rejects_names_postgresql_would_mangle() {
+        assert!(quote_ident("").is_err());
+        assert!(quote_ident("evil\0name").is_err());
+        assert!(quote_ident(&"a".repeat(64)).is_err());
+        assert!(quote_ident(&"a".repeat(63)).is_ok());
+        // Length is counted in bytes, like NAMEDATALEN.
+        assert!(quote_ident(&"ž".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn check_name_only_allows_lowercase() {
+        assert!(check_name("shop_db.v2").is_ok());
+        assert!(check_name("Shop").is_err());
+        assert!(check_name("shop-db").is_err());
+        assert!(check_name("").is_err());
+    }
+
+    #[test]
+    fn quote_literal_escapes_quotes_and_backslashes() {
+        assert_eq!(quote_literal("secret").unwrap(), "'secret'");
+        assert_eq!(quote_literal("it's").unwrap(), "'it''s'");
+        assert_eq!(quote_literal("a\\'b").unwrap(), "E'a\\\\''b'");
+        assert_eq!(
+            quote_literal("'; DROP ROLE postgres; --").unwrap(),
+            "'''; DROP ROLE postgres; --'"
+        );
+        assert!(quote_literal("nul\0").is_err());
+    }
+
+    #[test]
+    fn statements_quote_every_name() {
+        assert_eq!(
+            create_database("db\"x", "own'er", 10).unwrap(),
+            "CREATE DATABASE \"db\"\"x\" WITH OWNER \"own'er\" CONNECTION LIMIT 10"
+        );
+        assert_eq!(
+            create_user("u\"; DROP USER postgres; --", "pa'ss", -1).unwrap(),
+            "CREATE USER \"u\"\"; DROP USER postgres; --\" WITH PASSWORD 'pa''ss' CONNECTION LIMIT -1"
+        );
+        assert_eq!(
+            drop_database("x\" CASCADE; --").unwrap(),
+            "DROP DATABASE \"x\"\" CASCADE; --\""
+        );
+        assert_eq!(
+            terminate_backends("d'b", "app").unwrap(),
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = 'd''b' AND application_name = 'app' AND pid <> pg_backend_pid()"
+        );
+        assert_eq!(
+            comment_on_role("u", "orphaned by 'x'").unwrap(),
+            "COMMENT ON ROLE \"u\" IS 'orphaned by ''x'''"
+        );
+        assert_eq!(
+            create_extension("uuid-ossp").unwrap(),
+            "CREATE EXTENSION IF NOT EXISTS \"uuid-ossp\""
+        );
+        assert_eq!(
+            set_role_search_path("app", "db", "app\"s").unwrap(),
+            "ALTER ROLE \"app\" IN DATABASE \"db\" SET search_path = \"app\"\"s\", public"
+        );
+        assert!(alter_database_owner("db", "").is_err());
+        assert!(drop_user(&"u".repeat(100)).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+//! Splits a SQL script into statements, so a dump can be replayed as it downloads.
+//!
+//! Semicolons only end a statement outside of quoted strings, quoted
+//! identifiers, dollar quoted bodies and comments. `COPY ... FROM stdin` is
+//! followed by its data, which runs up to a line with just `\.`, like psql
+//! expects it.
+use crate::{Error, Result};
+
+pub enum Item {
+    /// A complete statement, without the semicolon.
+    Statement(String),
+    /// A `COPY ... FROM stdin` statement, followed by its data.
+    Copy(String),
+    /// Rows for the last `Copy`, in COPY text format.
+    CopyData(Vec<u8>),
+    /// All rows for the last `Copy` were read.
+    CopyDone,
+}
+
+enum Lex {
+    Normal,
+    /// Inside `'...'`, `escape` for `E'...'` where backslashes escape.
+    Single {
+        escape: bool,
+    },
+    /// Just after a closing `'`, which was an escaped one if another follows.
+    QuoteEnd {
+        escape: bool,
+    },
+    Double,
+    /// Inside a dollar quoted string, with its full `$tag$`.
+    Dollar(Vec<u8>),
+    LineComment,
+    /// Inside `/* */`, which nest.
+    Block(u32),
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Sql,
+    /// Rest of the line with the COPY statement, the data starts on the next one.
+    CopyLine,
+    CopyData,
+}
+
+pub struct Splitter {
+    buffer: Vec<u8>,
+    /// How far the current statement in `buffer` was scanned.
+    pos: usize,
+    lex: Lex,
+    /// Where the current statement starts, once anything but whitespace and comments was seen.
+    start: Option<usize>,
+    mode: Mode,
+}
+
+impl Default for Splitter {
+    fn default() -> Self {
+        Splitter {
+            buffer: Vec::new(),
+            pos: 0,
+            lex: Lex::Normal,
+            start: None,
+            mode: Mode::Sql,
+        }
+    }
+}
+
+fn is_ident(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$' || byte >= 0x80
+}
+
+impl Splitter {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Next complete item, `None` when more input is needed or, with `eof`, when it's done.
+    pub fn next_item(&mut self, eof: bool) -> Result<Option<Item>> {
+        match self.mode {
+            Mode::Sql => self.next_statement(eof),
+            Mode::CopyLine => match self.buffer.iter().position(|b| *b == b'\n') {
+                Some(newline) => {
+                    self.buffer.drain(..=newline);
+                    self.mode = Mode::CopyData;
+                    self.next_copy_data(eof)
+                }
+                None if eof => Err(Error::IllegalDump("unterminated COPY data".to_string())),
+                None => Ok(None),
+            },
+            Mode::CopyData => self.next_copy_data(eof),
+        }
+    }
+
+    fn next_statement(&mut self, eof: bool) -> Result<Option<Item>> {
+        // Lookahead past the end of the buffer has to wait for more input.
+        let peek = |buffer: &[u8], at: usize| buffer.get(at).copied();
+
+        while self.pos < self.buffer.len() {
+            let byte = self.buffer[self.pos];
+            match &self.lex {
+                Lex::Normal => match byte {
+                    b';' => {
+                        if let Some(item) = self.take_statement()? {
+                            return Ok(Some(item));
+                        }
+                        continue;
+                    }
+                    b'\'' => {
+                        let escape = self.pos >= 1
+                            && matches!(self.buffer[self.pos - 1], b'e' | b'E')
+                            && (self.pos < 2 || !is_ident(self.buffer[self.pos - 2]));
+                        self.lex = Lex::Single { escape };
+                        self.start.get_or_insert(self.pos);
+                    }
+                    b'"' => {
+                        self.lex = Lex::Double;
+                        self.start.get_or_insert(self.pos);
+                    }
+                    b'-' | b'/' => {
+                        let Some(next) = peek(&self.buffer, self.pos + 1) else {
+                            if !eof {
+                                return Ok(None);
+                            }
+                            self.start.get_or_insert(self.pos);
+                            self.pos += 1;
+                            continue;
+                        };
+                        match (byte, next) {
+                            (b'-', b'-') => self.lex = Lex::LineComment,
+                            (b'/', b'*') => self.lex = Lex::Block(1),
+                            _ => {
+                                self.start.get_or_insert(self.pos);
+                                self.pos += 1;
+                                continue;
+                            }
+                        }
+                        self.pos += 2;
+                        continue;
+                    }
+                    b'$' if self.pos == 0 || !is_ident(self.buffer[self.pos - 1]) => {
+                        self.start.get_or_insert(self.pos);
+                        let tag_end = self.buffer[self.pos + 1..]
+                            .iter()
+                            .position(|b| !(b.is_ascii_alphanumeric() || *b == b'_' || *b >= 0x80))
+                            .map(|len| self.pos + 1 + len);
+                        match tag_end {
+                            None if !eof => return Ok(None),
+                            Some(end)
+                                if self.buffer[end] == b'$'
+                                    && !self.buffer[self.pos + 1..end]
+                                        .first()
+                                        .is_some_and(u8::is_ascii_digit) =>
+                            {
+                                self.lex = Lex::Dollar(self.buffer[self.pos..=end].to_vec());
+                                self.pos = end + 1;
+                                continue;
+                            }
+                            // Positional parameters and the like.
+                            _ => {}
+                        }
+                    }
+                    byte if byte.is_ascii_whitespace() => {}
+                    _ => {
+                        self.start.get_or_insert(self.pos);
+                    }
+                },
+                Lex::Single { escape } => {
+                    if *escape && byte == b'\\' {
+                        if self.pos + 1 >= self.buffer.len() && !eof {
+                            return Ok(None);
+                        }
+                        self.pos += 2;
+                        continue;
+                    }
+                    if byte == b'\'' {
+                        self.lex = Lex::QuoteEnd { escape: *escape };
+                    }
+                }
+                Lex::QuoteEnd { escape } => {
+                    if byte == b'\'' {
+                        self.lex = Lex::Single { escape: *escape };
+                    } else {
+                        self.lex = Lex::Normal;
+                        continue;
+                    }
+                }
+                Lex::Double => {
+                    if byte == b'"' {
+                        self.lex = Lex::Normal;
+                    }
+                }
+                Lex::Dollar(tag) => {
+                    if byte == b'$' {
+                        let rest = &self.buffer[self.pos..];
+                        if rest.starts_with(tag) {
+                            self.pos += tag.len();
+                            self.lex = Lex::Normal;
+                            continue;
+                        }
+                        if rest.len() < tag.len() && tag.starts_with(rest) && !eof {
+                            return Ok(None);
+                        }
+                    }
+                }
+                Lex::LineComment => {
+                    if byte == b'\n' {
+                        self.lex = Lex::Normal;
+                    }
+                }
+                Lex::Block(depth) => {
+                    let depth = *depth;
+                    let Some(next) = peek(&self.buffer, self.pos + 1) else {
+                        if !eof {
+                            return Ok(None);
+                        }
+                        self.pos += 1;
+                        continue;
+                    };
+                    match (byte, next) {
+                        (b'*', b'/') if depth == 1 => self.lex = Lex::Normal,
+                        (b'*', b'/') => self.lex = Lex::Block(depth - 1),
+                        (b'/', b'*') => self.lex = Lex::Block(depth + 1),
+                        _ => {
+                            self.pos += 1;
+                            continue;
+                        }
+                    }
+                    self.pos += 2;
+                    continue;
+                }
+            }
+            self.pos += 1;
+        }
+
+        if !eof {
+            return Ok(None);
+        }
+        match self.lex {
+            Lex::Normal | Lex::QuoteEnd { .. } | Lex::LineComment => {}
+            Lex::Single { .. } | Lex::Double | Lex::Dollar(_) => {
+                return Err(Error::IllegalDump("unterminated quote".to_string()));
+            }
+            Lex::Block(_) => {
+                return Err(Error::IllegalDump("unterminated comment".to_string()));
+            }
+        }
+        // The last statement doesn't need a semicolon.
+        self.lex = Lex::Normal;
+        self.take_statement()
+    }
+
+    /// Cut the statement ending at `pos` off the buffer, `None` if it was empty.
+    fn take_statement(&mut self) -> Result<Option<Item>> {
+        let end = self.pos.min(self.buffer.len());
+        let statement = self
+            .start
+            .map(|start| String::from_utf8(self.buffer[start..end].to_vec()))
+            .transpose()
+            .map_err(|_| Error::IllegalDump("statement is not valid UTF-8".to_string()))?;
+        self.buffer.drain(..(end + 1).min(self.buffer.len()));
+        self.pos = 0;
+        self.start = None;
+
+        let Some(statement) = statement else {
+            return Ok(None);
+        };
+        let upper = statement.trim_end().to_ascii_uppercase();
+        if upper.starts_with("COPY ") && upper.ends_with("FROM STDIN") {
+            self.mode = Mode::CopyLine;
+            return Ok(Some(Item::Copy(statement)));
+        }
+        Ok(Some(Item::Statement(statement)))
+    }
+
+    fn next_copy_data(&mut self, eof: bool) -> Result<Option<Item>> {
+        // Only complete lines are looked at, data ends with a line holding just `\.`.
+        let mut end = 0;
+        while let Some(newline) = self.buffer[end..].iter().position(|b| *b == b'\n') {
+            let line = &self.buffer[end..end + newline];
+            if line == b"\\." {
+                if end > 0 {
+                    // The terminator is handled on the next call.
+                    break;
+                }
+                self.buffer.drain(..=newline);
+                self.mode = Mode::Sql;
+                return Ok(Some(Item::CopyDone));
+            }
+            end += newline + 1;
+        }
+
+        if end > 0 {
+            return Ok(Some(Item::CopyData(self.buffer.drain(..end).collect())));
+        }
+        if eof {
+            return Err(Error::IllegalDump("unterminated COPY data".to_string()));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Out {
+        Statement(String),
+        Copy(String),
+        Data(String),
+        Done,
+    }
+
+    /// Split `script`, feeding it `chunk` bytes at a time.
+    fn split(script: &str, chunk: usize) -> Result<Vec<Out>> {
+        let mut splitter = Splitter::default();
+        let mut out = Vec::new();
+        let mut data = String::new();
+        let mut collect = |item: Item, out: &mut Vec<Out>| match item {
+            Item::Statement(s) => out.push(Out::Statement(s)),
+            Item::Copy(s) => out.push(Out::Copy(s)),
+            Item::CopyData(d) => data.push_str(&String::from_utf8(d).unwrap()),
+            Item::CopyDone => {
+                out.push(Out::Data(std::mem::take(&mut data)));
+                out.push(Out::Done);
+            }
+        };
+        for part in script.as_bytes().chunks(chunk) {
+            splitter.push(part);
+            while let Some(item) = splitter.next_item(false)? {
+                collect(item, &mut out);
+            }
+        }
+        while let Some(item) = splitter.next_item(true)? {
+            collect(item, &mut out);
+        }
+        Ok(out)
+    }
+
+    fn statement(s: &str) -> Out {
+        Out::Statement(s.to_string())
+    }
+
+    /// Every chunking of the input has to give the same result.
+    fn assert_split(script: &str, expected: Vec<Out>) {
+        for chunk in [1, 2, 3, 7, script.len().max(1)] {
+            assert_eq!(
+                split(script, chunk).unwrap(),
+                expected,
+                "chunk size {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn splits_on_semicolons_outside_quotes() {
+        assert_split(
+            "SELECT 'a;b', \"c;\"\"d\"; SELECT 'it''s;';\nSELECT E'\\';' ;",
+            vec![
+                statement("SELECT 'a;b', \"c;\"\"d\""),
+                statement("SELECT 'it''s;'"),
+                statement("SELECT E'\\';' "),
+            ],
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_empty_statements() {
+        assert_split(
+            "-- Tables; not a statement\n;; /* one; /* nested; */ still; */ SELECT 1 - -1; SELECT 2/2",
+            vec![statement("SELECT 1 - -1"), statement("SELECT 2/2")],
+        );
+    }
+
+    #[test]
+    fn keeps_dollar_quoted_bodies_whole() {
+        let function =
+            "CREATE FUNCTION f() RETURNS int AS $body$ SELECT 1; $x$; $$ $body$ LANGUAGE sql";
+        assert_split(
+            &format!("{function}; DO $$ BEGIN PERFORM 1; END $$; SELECT $1, a$b FROM t;"),
+            vec![
+                statement(function),
+                statement("DO $$ BEGIN PERFORM 1; END $$"),
+                statement("SELECT $1, a$b FROM t"),
+            ],
+        );
+    }
+
+    #[test]
+    fn reads_copy_data_up_to_terminator() {
+        assert_split(
+            "COPY public.t (a, b) FROM stdin;\n1\t'; DROP TABLE t; --\n2\t\\\\.\n\\.\nSELECT 1;",
+            vec![
+                Out::Copy("COPY public.t (a, b) FROM stdin".to_string()),
+                Out::Data("1\t'; DROP TABLE t; --\n2\t\\\\.\n".to_string()),
+                Out::Done,
+                statement("SELECT 1"),
+            ],
+        );
+        assert_split(
+            "COPY t FROM stdin;\n\\.\n",
+            vec![
+                Out::Copy("COPY t FROM stdin".to_string()),
+                Out::Data(String::new()),
+                Out::Done,
+            ],
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_scripts() {
+        assert!(split("SELECT 'open", 4).is_err());
+        assert!(split("SELECT $$ open", 4).is_err());
+        assert!(split("/* open", 4).is_err());
+        assert!(split("COPY t FROM stdin;\n1\n", 4).is_err());
+    }
+}
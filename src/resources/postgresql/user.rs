@@ -1,27 +1,27 @@
-use crate::{Context, Error, FINALIZER, Result, telemetry};
-use chrono::Utc;
-use futures::StreamExt;
-use k8s_openapi::api::core::v1::Secret;
+use crate::{
+    Context, Error, Result,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::postgresql::{
+        database::Database,
+        login::{LoginSecret, generate_password, valid_key},
+        ownership_comment, ownership_marker, sql,
+    },
+    settings::Settings,
+};
+use chrono::{DateTime, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::{
     CustomResource, Resource,
-    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
-    client::Client as KubeClient,
-    core::ObjectMeta,
+    api::{Api, ListParams, ResourceExt},
     core::object::HasSpec,
-    runtime::{
-        controller::{Action, Controller},
-        events::{Event, EventType},
-        finalizer::{Event as Finalizer, finalizer},
-        watcher::Config,
-    },
 };
-use rand::distr::{Alphanumeric, SampleString};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::sync::Arc;
+use std::collections::BTreeMap;
 use tokio::time::Duration;
-use tracing::*;
+
+/// Annotation that rotates the password whenever its value changes.
+pub const ROTATE_ANNOTATION: &str = "postgresql.tjo.cloud/rotate";
 
 /// User on the postgresql.tjo.cloud database platform
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
@@ -32,15 +32,41 @@ use tracing::*;
     version = "v1",
     namespaced,
     shortname = "user",
-    status = "UserStatus"
+    status = "UserStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
+#[allow(non_snake_case)]
 pub struct UserSpec {
-    #[schemars(length(min = 3, max = 63), pattern(r"[a-z0-9._]+"))]
+    #[schemars(length(min = 3, max = 63), pattern(r"^[a-z0-9._]+$"))]
     pub name: String,
     pub server: String,
     /// Name of the secret that will be created and contain the generated password.
+    ///
+    /// A changed login is published as a new secret named after it, `status.passwordSecretName`
+    /// holds the one in use.
     pub passwordSecretName: String,
     pub connectionLimit: i32,
+    /// Extra secret keys, `{username}`, `{password}`, `{host}`, `{port}`, `{database}`, `{sslmode}`,
+    /// `{uri}` and `{jdbc-url}` in a template are replaced with the published values, they are
+    /// percent-encoded in templates containing `://`. Templates can't replace the built-in keys.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secretTemplates: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<Rotation>,
+    /// What happens to the role when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletionPolicy: Option<DeletionPolicy>,
+}
+
+/// Password rotation, on an interval and whenever the `postgresql.tjo.cloud/rotate` annotation changes.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Rotation {
+    /// Days between rotations, without it the password is only rotated through the annotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_days: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
@@ -49,204 +75,383 @@ pub struct UserRef {
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct UserStatus {
-    pub created: bool,
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    /// Connection limit applied on the last successful reconcile.
+    #[serde(default)]
+    pub connection_limit: i32,
+    /// When the password was last set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_rotation_time: Option<Time>,
+    /// Value of the rotate annotation that was last acted on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation_trigger: Option<String>,
+    /// Name the role was created under, later changes of the naming policy don't rename it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_name: Option<String>,
+    /// Secret holding the login now.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_secret_name: Option<String>,
+}
+
+impl ManagedStatus for UserStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+/// Live state of a role, as read from `pg_roles`.
+pub struct ObservedUser {
+    pub connection_limit: i32,
+    pub can_login: bool,
+}
+
+/// When the password is due next, `None` without an interval.
+fn next_rotation(rotation: &Rotation, last: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    rotation
+        .interval_days
+        .map(|days| last + chrono::Duration::days(days.into()))
 }
 
 impl User {
-    fn was_created(&self) -> bool {
-        self.status.as_ref().map(|s| s.created).unwrap_or(false)
+    /// Name of the role on the server, the naming policy only applies until it's recorded on the status.
+    pub fn backend_name(&self, settings: &Settings) -> Result<String> {
+        match self
+            .status
+            .as_ref()
+            .and_then(|status| status.backend_name.clone())
+        {
+            Some(name) => Ok(name),
+            None => settings
+                .naming
+                .apply(&self.namespace().unwrap(), &self.spec().name),
+        }
     }
 
-    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
-        let oref = self.object_ref(&());
-        let namespace = self.namespace().unwrap();
-        let name = self.name_any();
-        let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-        let secrets: Api<Secret> = Api::namespaced(ctx.kube_client.clone(), &namespace);
+    fn rotation_trigger(&self) -> Option<String> {
+        self.annotations().get(ROTATE_ANNOTATION).cloned()
+    }
 
-        // If was already created, do nothing.
-        if self.was_created() {
-            return Ok(Action::requeue(Duration::from_secs(5 * 60)));
+    /// When the password was set, falling back to the resource's creation for older statuses.
+    fn last_rotation(&self, status: &UserStatus) -> Option<DateTime<Utc>> {
+        status
+            .last_rotation_time
+            .as_ref()
+            .or(self.creation_timestamp().as_ref())
+            .map(|Time(time)| *time)
+    }
+
+    /// Why the password has to be rotated now, if it has to.
+    fn rotation_reason(&self, status: &UserStatus, now: DateTime<Utc>) -> Option<&'static str> {
+        let rotation = self.spec().rotation.as_ref()?;
+        let trigger = self.rotation_trigger();
+        if trigger.is_some() && trigger != status.rotation_trigger {
+            return Some("requested");
         }
+        let next = next_rotation(rotation, self.last_rotation(status)?)?;
+        (next <= now).then_some("scheduled")
+    }
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationRequested".into(),
-                    note: Some(format!("Creating user for `{name}`")),
-                    action: "Creating".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
+    /// The user's secret, naming the database of the first Database on the same server it owns.
+    pub(crate) async fn login_secret(&self, ctx: &Context) -> Result<LoginSecret<'_>> {
+        let namespace = self.namespace().unwrap();
+        let databases: Api<Database> = Api::namespaced(ctx.kube_client.clone(), &namespace);
+        let mut owned: Vec<_> = databases
+            .list(&ListParams::default())
             .await
-            .map_err(Error::KubeError)?;
+            .map_err(Error::KubeError)?
+            .into_iter()
+            .filter(|database| {
+                database.spec.ownerRef.name == self.name_any()
+                    && database.spec.server == self.spec().server
+            })
+            .collect();
+        owned.sort_by_key(|database| database.name_any());
+
+        Ok(LoginSecret {
+            namespace,
+            name: &self.spec().passwordSecretName,
+            current: self
+                .status
+                .as_ref()
+                .and_then(|status| status.password_secret_name.as_deref()),
+            owner: self.owner_ref(&()),
+            server: &self.spec().server,
+            role: self.backend_name(&ctx.settings)?,
+            database: owned
+                .into_iter()
+                .next()
+                .map(|database| database.backend_name(&ctx.settings))
+                .transpose()?,
+            templates: &self.spec().secretTemplates,
+        })
+    }
+}
+
+impl Managed for User {
+    type Observed = ObservedUser;
 
-        if name == "illegal" {
+    fn backend(&self) -> Backend {
+        Backend::Postgresql(self.spec().server.clone())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name_any() == "illegal" {
             return Err(Error::PostgresqlIllegalUser);
         }
+        sql::check_name(&self.spec().name)?;
+        if !self.spec().secretTemplates.keys().all(|key| valid_key(key)) {
+            return Err(Error::IllegalDocument);
+        }
+        Ok(())
+    }
 
-        if !ctx.postgresql_clients.contains_key(&self.spec().server) {
-            return Err(Error::PostgresqlUnknownServer);
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletionPolicy
+    }
+
+    fn identity(&self, settings: &Settings, _status: &UserStatus) -> Option<String> {
+        self.global_name(settings).ok().flatten()
+    }
+
+    fn global_name(&self, settings: &Settings) -> Result<Option<String>> {
+        Ok(Some(format!(
+            "{}/{}",
+            self.spec().server,
+            self.backend_name(settings)?
+        )))
+    }
+
+    /// An existing role is taken over as it is, without its secret the update sets a new password.
+    async fn adopt(&self, ctx: &Context, status: &mut UserStatus) -> Result<bool> {
+        if self.observe(ctx).await?.is_none() {
+            return Ok(false);
         }
+        status.backend_name = Some(self.backend_name(&ctx.settings)?);
+        Ok(true)
+    }
 
-        let password = Alphanumeric.sample_string(&mut rand::rng(), 16);
+    fn requeue_after(&self, status: &UserStatus) -> Duration {
+        let resync = Duration::from_secs(5 * 60);
+        self.spec()
+            .rotation
+            .as_ref()
+            .zip(self.last_rotation(status))
+            .and_then(|(rotation, last)| next_rotation(rotation, last))
+            .and_then(|next| (next - Utc::now()).to_std().ok())
+            .map_or(resync, |until| until.min(resync))
+    }
 
+    async fn marker(&self, ctx: &Context) -> Result<Option<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let row = ctx.postgresql_clients[&self.spec().server]
+            .query_opt(
+                "SELECT shobj_description(oid, 'pg_authid') FROM pg_roles WHERE rolname = $1",
+                &[&name],
+            )
+            .await?;
+        let comment: Option<String> = row.and_then(|row| row.get(0));
+        Ok(comment
+            .as_deref()
+            .and_then(ownership_marker)
+            .map(str::to_string))
+    }
+
+    async fn mark(&self, ctx: &Context, uid: &str) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
         ctx.postgresql_clients[&self.spec().server]
-            .execute(
-                &format!(
-                    "CREATE USER {} WITH PASSWORD '{}' CONNECTION LIMIT {}",
-                    self.spec().name.clone(),
-                    password,
-                    self.spec().connectionLimit
-                ),
-                &[],
+            .execute(&sql::comment_on_role(&name, &ownership_comment(uid))?, &[])
+            .await?;
+        Ok(())
+    }
+
+    async fn observe(&self, ctx: &Context) -> Result<Option<ObservedUser>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let row = ctx.postgresql_clients[&self.spec().server]
+            .query_opt(
+                "SELECT rolconnlimit, rolcanlogin FROM pg_roles WHERE rolname = $1",
+                &[&name],
             )
             .await?;
 
-        let server_host_name = ctx.settings.postgresql[&self.spec().server].host.clone();
+        Ok(row.map(|row| ObservedUser {
+            connection_limit: row.get(0),
+            can_login: row.get(1),
+        }))
+    }
 
-        let secret = Secret {
-            metadata: ObjectMeta {
-                name: Some(self.spec().passwordSecretName.clone()),
-                owner_references: Some(self.owner_ref(&()).into_iter().collect()),
-                ..Default::default()
-            },
-            immutable: Some(true),
-            string_data: Some(std::collections::BTreeMap::from([
-                ("password".to_string(), password),
-                ("username".to_string(), self.spec().name.clone()),
-                ("host".to_string(), server_host_name),
-                ("port".to_string(), "5432".to_string()),
-            ])),
-            ..Default::default()
+    async fn create(&self, ctx: &Context, status: &mut UserStatus) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        let login = self.login_secret(ctx).await?;
+        // A role dropped out-of-band comes back with the password its secret still holds.
+        let password = match login.password(ctx).await? {
+            Some(password) => password,
+            None => generate_password(),
         };
 
-        secrets
-            .create(&kube::api::PostParams::default(), &secret)
-            .await
-            .map_err(Error::KubeError)?;
-
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationCompleted".into(),
-                    note: Some(format!("Created user for `{name}`")),
-                    action: "Created".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
+        // Marked together with the role, so when publishing the secret fails the next pass
+        // finds its own role and the update sets a new password.
+        let mut client = ctx.postgresql_clients[&self.spec().server].get().await?;
+        let tx = client.transaction().await?;
+        tx.batch_execute(&sql::create_user(
+            &name,
+            &password,
+            self.spec().connectionLimit,
+        )?)
+        .await?;
+        tx.batch_execute(&sql::comment_on_role(
+            &name,
+            &ownership_comment(&self.uid().unwrap_or_default()),
+        )?)
+        .await?;
+        tx.commit().await?;
+        status.reconcile.marked = true;
+        status.backend_name = Some(name);
 
-        let new_status = Patch::Apply(json!({
-            "apiVersion": "postgresql.tjo.cloud/v1",
-            "kind": "User",
-            "status": UserStatus {
-                created : true,
-            }
-        }));
-        let ps = PatchParams::apply("cntrlr").force();
-        users
-            .patch_status(&name, &ps, &new_status)
-            .await
-            .map_err(Error::KubeError)?;
-
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
-    }
-
-    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
-        let oref = self.object_ref(&());
-        let name = self.name_any();
-
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "DeleteRequested".into(),
-                    note: Some(format!("Dropping user for `{}`", name)),
-                    action: "Deleting".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
+        let published = login.publish(ctx, password).await?;
+        status.password_secret_name = Some(login.switch_over(ctx, published).await?);
+
+        status.connection_limit = self.spec().connectionLimit;
+        status.last_rotation_time = Some(Time(Utc::now()));
+        status.rotation_trigger = self.rotation_trigger();
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        ctx: &Context,
+        observed: ObservedUser,
+        status: &mut UserStatus,
+    ) -> Result<Vec<String>> {
+        let name = self.backend_name(&ctx.settings)?;
+        let client = &ctx.postgresql_clients[&self.spec().server];
+        let mut changes = Vec::new();
+
+        if observed.connection_limit != self.spec().connectionLimit {
+            client
+                .execute(
+                    &sql::alter_role_connection_limit(&name, self.spec().connectionLimit)?,
+                    &[],
+                )
+                .await?;
+            changes.push(format!(
+                "connection limit {} -> {}",
+                observed.connection_limit,
+                self.spec().connectionLimit
+            ));
+        }
+
+        if !observed.can_login {
+            client.execute(&sql::alter_role_login(&name)?, &[]).await?;
+            changes.push("login enabled".to_string());
+        }
 
-        if !ctx.postgresql_clients.contains_key(&self.spec().server) {
-            return Err(Error::PostgresqlUnknownServer);
+        // Without the secret nobody knows the password, so set a new one.
+        let login = self.login_secret(ctx).await?;
+        let published = login.password(ctx).await?;
+        let now = Utc::now();
+        let reason = match published {
+            None => Some("secret missing"),
+            Some(_) => self.rotation_reason(status, now),
+        };
+        let password = match (reason, published) {
+            (None, Some(password)) => password,
+            _ => generate_password(),
+        };
+        // Also picks up a new database, server setting or template. A new password is published
+        // first, the secret in use keeps working until the role has it.
+        let published = login.publish(ctx, password.clone()).await?;
+        if let Some(reason) = reason {
+            let altered = client
+                .execute(&sql::alter_role_password(&name, &password)?, &[])
+                .await;
+            if let Err(err) = altered {
+                login.discard(ctx, published).await?;
+                return Err(err);
+            }
+            changes.push(format!("password rotated ({reason})"));
+            status.last_rotation_time = Some(Time(now));
+        } else if published.is_some() {
+            changes.push("secret updated".to_string());
         }
+        status.password_secret_name = Some(login.switch_over(ctx, published).await?);
+
+        status.backend_name = Some(name);
+        status.connection_limit = self.spec().connectionLimit;
+        status.rotation_trigger = self.rotation_trigger();
+        Ok(changes)
+    }
 
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
         ctx.postgresql_clients[&self.spec().server]
-            .execute(&format!("DROP USER {}", self.spec().name.clone()), &[])
+            .execute(&sql::drop_user(&name)?, &[])
             .await?;
-
-        Ok(Action::await_change())
+        Ok(())
     }
-}
 
-#[instrument(skip(ctx, user), fields(trace_id))]
-async fn reconcile(user: Arc<User>, ctx: Arc<Context>) -> Result<Action> {
-    let oref = user.object_ref(&());
-
-    let trace_id = telemetry::get_trace_id();
-    if trace_id != opentelemetry::trace::TraceId::INVALID {
-        Span::current().record("trace_id", field::display(&trace_id));
-    }
-    let _timer = ctx.metrics.reconcile.count_and_measure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        &trace_id,
-    );
-    ctx.diagnostics.write().await.last_event = Utc::now();
-    let ns = user.namespace().unwrap();
-    let users: Api<User> = Api::namespaced(ctx.kube_client.clone(), &ns);
-
-    info!("Reconciling User \"{}\" in {}", user.name_any(), ns);
-    finalizer(&users, FINALIZER, user, |event| async {
-        match event {
-            Finalizer::Apply(user) => user.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(user) => user.cleanup(ctx.clone()).await,
-        }
-    })
-    .await
-    .map_err(|e| Error::FinalizerError(Box::new(e)))
+    async fn orphan(&self, ctx: &Context, note: &str) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        ctx.postgresql_clients[&self.spec().server]
+            .execute(&sql::comment_on_role(&name, note)?, &[])
+            .await?;
+        Ok(())
+    }
 }
 
-fn error_policy(user: Arc<User>, error: &Error, ctx: Arc<Context>) -> Action {
-    error!("reconcile failed: {:?}", error);
-    let oref = user.object_ref(&());
-
-    ctx.metrics.reconcile.set_failure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        user.name_any(),
-        error,
-    );
-    Action::requeue(Duration::from_secs(5 * 60))
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
 
-/// Initialize the controller and shared state (given the crd is installed)
-pub async fn run(context: Arc<Context>, kube_client: KubeClient) -> Result<(), Error> {
-    let users = Api::<User>::all(kube_client.clone());
-    match users.list(&ListParams::default().limit(1)).await {
-        Err(err) => return Err(Error::MissingCrds(err)),
-        Ok(_) => info!("CRDs for User are installed!"),
-    };
+    fn user(rotation: Option<Rotation>, trigger: Option<&str>) -> User {
+        let mut user = User::new(
+            "example",
+            UserSpec {
+                rotation,
+                ..Default::default()
+            },
+        );
+        if let Some(trigger) = trigger {
+            user.annotations_mut()
+                .insert(ROTATE_ANNOTATION.to_string(), trigger.to_string());
+        }
+        user
+    }
 
-    info!("Starting controller");
+    #[test]
+    fn rotation_follows_interval_and_annotation() {
+        let set = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let status = UserStatus {
+            last_rotation_time: Some(Time(set)),
+            rotation_trigger: Some("1".to_string()),
+            ..Default::default()
+        };
+        let weekly = Rotation {
+            interval_days: Some(7),
+        };
 
-    Controller::new(users, Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
-        .await;
+        let now = set + chrono::Duration::days(6);
+        assert_eq!(user(None, Some("2")).rotation_reason(&status, now), None);
+        assert_eq!(
+            user(Some(weekly.clone()), Some("1")).rotation_reason(&status, now),
+            None
+        );
+        assert_eq!(
+            user(Some(Rotation::default()), Some("2")).rotation_reason(&status, now),
+            Some("requested")
+        );
 
-    Ok(())
+        let now = set + chrono::Duration::days(7);
+        assert_eq!(
+            user(Some(weekly), Some("1")).rotation_reason(&status, now),
+            Some("scheduled")
+        );
+    }
 }
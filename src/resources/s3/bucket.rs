@@ -1,24 +1,15 @@
-use crate::{Context, Error, FINALIZER, Result, telemetry};
-use chrono::Utc;
-use futures::StreamExt;
-use kube::{
-    CustomResource, Resource,
-    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
-    client::Client as KubeClient,
-    core::object::HasSpec,
-    runtime::{
-        controller::{Action, Controller},
-        events::{Event, EventType},
-        finalizer::{Event as Finalizer, finalizer},
-        watcher::Config,
-    },
+use crate::{
+    Context, Error, Result,
+    garage::Bucket as GarageBucket,
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    settings::Settings,
 };
+use kube::{CustomResource, ResourceExt, core::object::HasSpec};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::sync::Arc;
-use tokio::time::Duration;
-use tracing::*;
+
+/// Buckets are marked with an extra global alias holding the uid of the Bucket managing them.
+const MARKER_ALIAS_PREFIX: &str = "console-";
 
 /// Represents a bucket in s3.tjo.cloud.
 ///
@@ -30,11 +21,18 @@ use tracing::*;
     version = "v1",
     namespaced,
     shortname = "buc",
-    status = "BucketStatus"
+    status = "BucketStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
+#[allow(non_snake_case)]
 pub struct BucketSpec {
-    #[schemars(length(min = 3, max = 63), pattern(r"[a-z0-9.-_]+"))]
+    #[schemars(length(min = 3, max = 63), pattern(r"^[a-z0-9._-]+$"))]
     pub name: String,
+    /// What happens to the bucket when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletionPolicy: Option<DeletionPolicy>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
@@ -43,14 +41,53 @@ pub struct BucketRef {
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct BucketStatus {
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    #[serde(default)]
     pub id: String,
-    pub created: bool,
+    /// Global alias the bucket was created under, later changes of the naming policy don't rename it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend_name: Option<String>,
+}
+
+impl ManagedStatus for BucketStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
+    }
+}
+
+fn marker_alias(uid: &str) -> String {
+    format!("{MARKER_ALIAS_PREFIX}{uid}")
+}
+
+/// Uid in the marker alias, aliases that merely start like one are skipped.
+fn marker_of(aliases: &[String]) -> Option<&str> {
+    aliases.iter().find_map(|alias| {
+        let uid = alias.strip_prefix(MARKER_ALIAS_PREFIX)?;
+        let is_uid = uid.len() == 36 && uid.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+        is_uid.then_some(uid)
+    })
 }
 
 impl Bucket {
-    fn was_created(&self) -> bool {
-        self.status.as_ref().map(|s| s.created).unwrap_or(false)
+    /// Global alias of the bucket, the naming policy only applies until it's recorded on the status.
+    pub fn backend_name(&self, settings: &Settings) -> Result<String> {
+        match self
+            .status
+            .as_ref()
+            .and_then(|status| status.backend_name.clone())
+        {
+            Some(name) => Ok(name),
+            None => settings
+                .naming
+                .apply(&self.namespace().unwrap(), &self.spec().name),
+        }
     }
 
     pub fn get_id(&self) -> String {
@@ -59,154 +96,163 @@ impl Bucket {
             .map(|s| s.id.clone())
             .unwrap_or("".to_string())
     }
+}
 
-    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
-        let garage_client = ctx.garage_client.clone();
-        let oref = self.object_ref(&());
-        let namespace = self.namespace().unwrap();
-        let name = self.name_any();
-        let buckets: Api<Bucket> = Api::namespaced(ctx.kube_client.clone(), &namespace);
+impl Managed for Bucket {
+    type Observed = GarageBucket;
 
-        // If was already created, do nothing.
-        if self.was_created() {
-            return Ok(Action::requeue(Duration::from_secs(5 * 60)));
-        }
+    fn backend(&self) -> Backend {
+        Backend::Garage
+    }
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationRequested".into(),
-                    note: Some(format!("Creating bucket for `{name}`")),
-                    action: "Creating".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletionPolicy
+    }
+
+    fn identity(&self, _settings: &Settings, status: &BucketStatus) -> Option<String> {
+        (!status.id.is_empty()).then(|| status.id.clone())
+    }
 
-        let bucket = garage_client
-            .create_bucket(self.spec().name.clone())
+    /// Existing buckets are found through their global alias.
+    async fn adopt(&self, ctx: &Context, status: &mut BucketStatus) -> Result<bool> {
+        let bucket = ctx
+            .garage_client
+            .get_bucket_by_alias(self.backend_name(&ctx.settings)?)
             .await
             .map_err(Error::GarageClientError)?;
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationCompleted".into(),
-                    note: Some(format!("Created bucket for `{name}`")),
-                    action: "Created".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
+        let Some(bucket) = bucket else {
+            return Ok(false);
+        };
+        status.id = bucket.id;
+        status.backend_name = Some(self.backend_name(&ctx.settings)?);
+        Ok(true)
+    }
+
+    async fn marker(&self, ctx: &Context) -> Result<Option<String>> {
+        let Some(bucket) = self.observe(ctx).await? else {
+            return Ok(None);
+        };
+        Ok(marker_of(&bucket.global_aliases).map(str::to_string))
+    }
+
+    async fn mark(&self, ctx: &Context, uid: &str) -> Result<()> {
+        ctx.garage_client
+            .add_bucket_alias(self.get_id(), marker_alias(uid))
             .await
-            .map_err(Error::KubeError)?;
-
-        let new_status = Patch::Apply(json!({
-            "apiVersion": "s3.tjo.cloud/v1",
-            "kind": "Bucket",
-            "status": BucketStatus {
-                created : true,
-                id : bucket.id,
-            }
-        }));
-        let ps = PatchParams::apply("cntrlr").force();
-        buckets
-            .patch_status(&name, &ps, &new_status)
+            .map_err(Error::GarageClientError)
+    }
+
+    /// Global aliases are shared by every bucket in the cluster.
+    fn global_name(&self, settings: &Settings) -> Result<Option<String>> {
+        self.backend_name(settings).map(Some)
+    }
+
+    async fn observe(&self, ctx: &Context) -> Result<Option<GarageBucket>> {
+        if self.get_id().is_empty() {
+            return Ok(None);
+        }
+        ctx.garage_client
+            .get_bucket(self.get_id())
             .await
-            .map_err(Error::KubeError)?;
-
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
-    }
-
-    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
-        let garage_client = ctx.garage_client.clone();
-        let oref = self.object_ref(&());
-
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "DeleteRequested".into(),
-                    note: Some(format!("Deleting bucket for `{}`", self.name_any())),
-                    action: "Deleting".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
+            .map_err(Error::GarageClientError)
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut BucketStatus) -> Result<()> {
+        let name = self.backend_name(&ctx.settings)?;
+        let uid = self.uid().unwrap_or_default();
+        // Buckets are only observed by id, so a taken alias is caught here.
+        if let Some(bucket) = ctx
+            .garage_client
+            .get_bucket_by_alias(name.clone())
             .await
-            .map_err(Error::KubeError)?;
+            .map_err(Error::GarageClientError)?
+        {
+            // An earlier pass created and marked it, but couldn't record its id.
+            if marker_of(&bucket.global_aliases) != Some(uid.as_str()) {
+                return Err(Error::AlreadyExists(format!("bucket `{name}`")));
+            }
+            status.id = bucket.id;
+            status.reconcile.marked = true;
+            status.backend_name = Some(name);
+            return Ok(());
+        }
 
-        garage_client
-            .delete_bucket(self.get_id())
+        let bucket = ctx
+            .garage_client
+            .create_bucket(name.clone())
             .await
             .map_err(Error::GarageClientError)?;
+        // Marked right away, so a later pass recognises the bucket even when this one fails to record it.
+        if let Err(err) = ctx
+            .garage_client
+            .add_bucket_alias(bucket.id.clone(), marker_alias(&uid))
+            .await
+        {
+            ctx.garage_client
+                .delete_bucket(bucket.id)
+                .await
+                .map_err(Error::GarageClientError)?;
+            return Err(Error::GarageClientError(err));
+        }
 
-        Ok(Action::await_change())
+        status.id = bucket.id;
+        status.reconcile.marked = true;
+        status.backend_name = Some(name);
+        Ok(())
     }
-}
 
-#[instrument(skip(ctx, bucket), fields(trace_id))]
-async fn reconcile(bucket: Arc<Bucket>, ctx: Arc<Context>) -> Result<Action> {
-    let oref = bucket.object_ref(&());
-
-    let trace_id = telemetry::get_trace_id();
-    if trace_id != opentelemetry::trace::TraceId::INVALID {
-        Span::current().record("trace_id", field::display(&trace_id));
-    }
-    let _timer = ctx.metrics.reconcile.count_and_measure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        &trace_id,
-    );
-    ctx.diagnostics.write().await.last_event = Utc::now();
-    let ns = bucket.namespace().unwrap();
-    let buckets: Api<Bucket> = Api::namespaced(ctx.kube_client.clone(), &ns);
-
-    info!("Reconciling Bucket \"{}\" in {}", bucket.name_any(), ns);
-    finalizer(&buckets, FINALIZER, bucket, |event| async {
-        match event {
-            Finalizer::Apply(bucket) => bucket.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(bucket) => bucket.cleanup(ctx.clone()).await,
-        }
-    })
-    .await
-    .map_err(|e| Error::FinalizerError(Box::new(e)))
-}
+    async fn update(
+        &self,
+        ctx: &Context,
+        _observed: GarageBucket,
+        status: &mut BucketStatus,
+    ) -> Result<Vec<String>> {
+        status.backend_name = Some(self.backend_name(&ctx.settings)?);
+        Ok(Vec::new())
+    }
 
-fn error_policy(bucket: Arc<Bucket>, error: &Error, ctx: Arc<Context>) -> Action {
-    error!("reconcile failed: {:?}", error);
-    let oref = bucket.object_ref(&());
-
-    ctx.metrics.reconcile.set_failure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        bucket.name_any(),
-        error,
-    );
-    Action::requeue(Duration::from_secs(5 * 60))
-}
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        ctx.garage_client
+            .delete_bucket(self.get_id())
+            .await
+            .map_err(Error::GarageClientError)
+    }
 
-/// Initialize the controller and shared state (given the crd is installed)
-pub async fn run(context: Arc<Context>, kube_client: KubeClient) -> Result<(), Error> {
-    let buckets = Api::<Bucket>::all(kube_client.clone());
-    match buckets.list(&ListParams::default().limit(1)).await {
-        Err(err) => return Err(Error::MissingCrds(err)),
-        Ok(_) => info!("CRDs for Bucket are installed!"),
-    };
+    /// Garage buckets carry no description, so the bucket gets an extra `orphaned-` alias instead.
+    ///
+    /// The marker alias goes, the bucket is no longer managed.
+    async fn orphan(&self, ctx: &Context, _note: &str) -> Result<()> {
+        let mut alias = format!("orphaned-{}", self.backend_name(&ctx.settings)?);
+        alias.truncate(63);
+        ctx.garage_client
+            .add_bucket_alias(self.get_id(), alias)
+            .await
+            .map_err(Error::GarageClientError)?;
 
-    info!("Starting controller");
+        if let Some(uid) = self.marker(ctx).await? {
+            ctx.garage_client
+                .remove_bucket_alias(self.get_id(), marker_alias(&uid))
+                .await
+                .map_err(Error::GarageClientError)?;
+        }
+        Ok(())
+    }
+}
 
-    Controller::new(buckets, Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
-        .await;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn marker_alias_round_trips() {
+        let uid = "0b7f4a8e-7a4c-4a5e-9d0e-2f1c3b4a5d6e";
+        let aliases = vec![
+            "console-shop".to_string(),
+            "shop".to_string(),
+            marker_alias(uid),
+        ];
+        assert_eq!(marker_of(&aliases), Some(uid));
+        assert_eq!(marker_of(&aliases[..2]), None);
+    }
 }
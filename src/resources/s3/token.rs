@@ -1,30 +1,22 @@
 use crate::{
-    BucketPermissions, Context, Error, FINALIZER, Result,
-    resources::s3::bucket::{Bucket, BucketRef},
-    telemetry,
+    BucketPermissions, Context, Error, Result,
+    garage::{Key, KeyInfo},
+    reconciler::{Backend, DeletionPolicy, Managed, ManagedStatus, ReconcileStatus},
+    resources::{
+        replace_secret,
+        s3::bucket::{Bucket, BucketRef},
+    },
+    settings::Settings,
 };
-use chrono::Utc;
-use futures::StreamExt;
 use k8s_openapi::api::core::v1::Secret;
 use kube::{
     CustomResource, Resource,
-    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
-    client::Client as KubeClient,
+    api::{Api, ResourceExt},
     core::ObjectMeta,
     core::object::HasSpec,
-    runtime::{
-        controller::{Action, Controller},
-        events::{Event, EventType},
-        finalizer::{Event as Finalizer, finalizer},
-        watcher::Config,
-    },
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::sync::Arc;
-use tokio::time::Duration;
-use tracing::*;
 
 /// Represents a token to access bucket in s3.tjo.cloud.
 ///
@@ -36,29 +28,57 @@ use tracing::*;
     version = "v1",
     namespaced,
     shortname = "tok",
-    status = "TokenStatus"
+    status = "TokenStatus",
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Reason", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].reason"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
+#[allow(non_snake_case)]
 pub struct TokenSpec {
-    #[allow(non_snake_case)]
     pub bucketRef: BucketRef,
     pub tokenSecretName: String,
     pub name: String,
     pub reader: bool,
     pub writer: bool,
     pub owner: bool,
+    /// What happens to the key when this resource is deleted, defaults to the controller setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletionPolicy: Option<DeletionPolicy>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct TokenStatus {
-    pub created: bool,
+    #[serde(flatten)]
+    pub reconcile: ReconcileStatus,
+    #[serde(default)]
     pub id: String,
 }
 
-impl Token {
-    fn was_created(&self) -> bool {
-        self.status.as_ref().map(|s| s.created).unwrap_or(false)
+impl ManagedStatus for TokenStatus {
+    fn reconcile(&self) -> &ReconcileStatus {
+        &self.reconcile
+    }
+
+    fn reconcile_mut(&mut self) -> &mut ReconcileStatus {
+        &mut self.reconcile
     }
+}
+
+/// Key name marked with the uid of the Token managing it, as in `name [uid]`.
+fn marked_name(name: &str, uid: &str) -> String {
+    format!("{name} [{uid}]")
+}
 
+/// Name and marker of a key, `None` for keys console didn't mark.
+fn split_marker(key_name: &str) -> (&str, Option<&str>) {
+    key_name
+        .strip_suffix(']')
+        .and_then(|rest| rest.rsplit_once(" ["))
+        .map_or((key_name, None), |(name, uid)| (name, Some(uid)))
+}
+
+impl Token {
     fn get_id(&self) -> String {
         self.status
             .as_ref()
@@ -66,38 +86,17 @@ impl Token {
             .unwrap_or("".to_string())
     }
 
-    pub async fn reconcile(&self, ctx: Arc<Context>) -> Result<Action> {
-        let garage_client = ctx.garage_client.clone();
-        let oref = self.object_ref(&());
-        let namespace = self.namespace().unwrap();
-        let name = self.name_any();
-        let tokens: Api<Token> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-        let buckets: Api<Bucket> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-        let secrets: Api<Secret> = Api::namespaced(ctx.kube_client.clone(), &namespace);
-
-        // If was already created, do nothing.
-        if self.was_created() {
-            return Ok(Action::requeue(Duration::from_secs(5 * 60)));
+    fn permissions(&self) -> BucketPermissions {
+        BucketPermissions {
+            read: self.spec().reader,
+            write: self.spec().writer,
+            owner: self.spec().owner,
         }
+    }
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationRequested".into(),
-                    note: Some(format!("Creating token for `{name}`")),
-                    action: "Creating".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
-            .await
-            .map_err(Error::KubeError)?;
-
-        let key = garage_client
-            .create_key(self.spec().name.clone())
-            .await
-            .map_err(Error::GarageClientError)?;
+    async fn publish_secret(&self, ctx: &Context, key: &Key) -> Result<()> {
+        let secrets: Api<Secret> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
 
         let secret = Secret {
             metadata: ObjectMeta {
@@ -113,146 +112,185 @@ impl Token {
             ..Default::default()
         };
 
-        secrets
-            .create(&kube::api::PostParams::default(), &secret)
-            .await
-            .map_err(Error::KubeError)?;
+        replace_secret(&secrets, &secret).await
+    }
 
-        let bucket = buckets
+    async fn bucket(&self, ctx: &Context) -> Result<Bucket> {
+        let buckets: Api<Bucket> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        buckets
             .get(&self.spec().bucketRef.name)
             .await
-            .map_err(Error::KubeError)?;
-
-        garage_client
-            .clone()
-            .set_bucket_permissions(
-                bucket.get_id(),
-                key.id.clone(),
-                BucketPermissions {
-                    read: self.spec().reader,
-                    write: self.spec().writer,
-                    owner: self.spec().owner,
-                },
-            )
-            .await
-            .map_err(Error::GarageClientError)?;
+            .map_err(Error::KubeError)
+    }
+}
+
+impl Managed for Token {
+    type Observed = KeyInfo;
 
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "CreationCompleted".into(),
-                    note: Some(format!("Created token for `{name}`")),
-                    action: "Created".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
+    fn backend(&self) -> Backend {
+        Backend::Garage
+    }
+
+    fn deletion_policy(&self) -> Option<DeletionPolicy> {
+        self.spec().deletionPolicy
+    }
+
+    fn identity(&self, _settings: &Settings, status: &TokenStatus) -> Option<String> {
+        (!status.id.is_empty()).then(|| status.id.clone())
+    }
+
+    /// Existing keys are found by name, which has to be unique, the update publishes their secret.
+    async fn adopt(&self, ctx: &Context, status: &mut TokenStatus) -> Result<bool> {
+        let matching: Vec<_> = ctx
+            .garage_client
+            .list_keys()
             .await
-            .map_err(Error::KubeError)?;
-
-        let new_status = Patch::Apply(json!({
-            "apiVersion": "s3.tjo.cloud/v1",
-            "kind": "Token",
-            "status": TokenStatus {
-                created : true,
-                id : key.id,
+            .map_err(Error::GarageClientError)?
+            .into_iter()
+            .filter(|key| split_marker(&key.name).0 == self.spec().name)
+            .collect();
+
+        let id = match matching.as_slice() {
+            [] => return Ok(false),
+            [key] => key.id.clone(),
+            keys => {
+                return Err(Error::AdoptionFailed(format!(
+                    "{} keys are named `{}`",
+                    keys.len(),
+                    self.spec().name
+                )));
             }
-        }));
-        let ps = PatchParams::apply("cntrlr").force();
-        tokens
-            .patch_status(&name, &ps, &new_status)
-            .await
-            .map_err(Error::KubeError)?;
+        };
+        status.id = id;
+        Ok(true)
+    }
 
-        // If no events were received, check back every 5 minutes
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
+    async fn marker(&self, ctx: &Context) -> Result<Option<String>> {
+        let Some(key) = self.observe(ctx).await? else {
+            return Ok(None);
+        };
+        Ok(split_marker(&key.name).1.map(str::to_string))
     }
 
-    pub async fn cleanup(&self, ctx: Arc<Context>) -> Result<Action> {
-        let garage_client = ctx.garage_client.clone();
-        let oref = self.object_ref(&());
-
-        ctx.recorder
-            .publish(
-                &Event {
-                    type_: EventType::Normal,
-                    reason: "DeleteRequested".into(),
-                    note: Some(format!("Deleting token for `{}`", self.name_any())),
-                    action: "Deleting".into(),
-                    secondary: None,
-                },
-                &oref,
-            )
+    async fn mark(&self, ctx: &Context, uid: &str) -> Result<()> {
+        ctx.garage_client
+            .update_key_name(self.get_id(), marked_name(&self.spec().name, uid))
             .await
-            .map_err(Error::KubeError)?;
+            .map_err(Error::GarageClientError)
+    }
 
-        garage_client
-            .delete_key(self.get_id())
+    async fn observe(&self, ctx: &Context) -> Result<Option<KeyInfo>> {
+        if self.get_id().is_empty() {
+            return Ok(None);
+        }
+        ctx.garage_client
+            .get_key(self.get_id())
+            .await
+            .map_err(Error::GarageClientError)
+    }
+
+    async fn create(&self, ctx: &Context, status: &mut TokenStatus) -> Result<()> {
+        let key = ctx
+            .garage_client
+            .create_key(self.spec().name.clone())
             .await
             .map_err(Error::GarageClientError)?;
 
-        Ok(Action::await_change())
+        let result = async {
+            self.publish_secret(ctx, &key).await?;
+            let bucket = self.bucket(ctx).await?;
+            ctx.garage_client
+                .set_bucket_permissions(bucket.get_id(), key.id.clone(), self.permissions())
+                .await
+                .map_err(Error::GarageClientError)
+        }
+        .await;
+        if let Err(err) = result {
+            // Nothing recorded the key yet, so the next attempt would leave it behind.
+            ctx.garage_client
+                .delete_key(key.id)
+                .await
+                .map_err(Error::GarageClientError)?;
+            return Err(err);
+        }
+
+        status.id = key.id;
+        Ok(())
     }
-}
 
-#[instrument(skip(ctx, token), fields(trace_id))]
-async fn reconcile(token: Arc<Token>, ctx: Arc<Context>) -> Result<Action> {
-    let oref = token.object_ref(&());
+    async fn update(
+        &self,
+        ctx: &Context,
+        observed: KeyInfo,
+        _status: &mut TokenStatus,
+    ) -> Result<Vec<String>> {
+        let bucket = self.bucket(ctx).await?;
+        let permissions = self.permissions();
+        let mut changes = Vec::new();
 
-    let trace_id = telemetry::get_trace_id();
-    if trace_id != opentelemetry::trace::TraceId::INVALID {
-        Span::current().record("trace_id", field::display(&trace_id));
-    }
-    let _timer = ctx.metrics.reconcile.count_and_measure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        &trace_id,
-    );
-    ctx.diagnostics.write().await.last_event = Utc::now();
-    let ns = token.namespace().unwrap();
-    let tokens: Api<Token> = Api::namespaced(ctx.kube_client.clone(), &ns);
-
-    info!("Reconciling Token \"{}\" in {}", token.name_any(), ns);
-    finalizer(&tokens, FINALIZER, token, |event| async {
-        match event {
-            Finalizer::Apply(token) => token.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(token) => token.cleanup(ctx.clone()).await,
+        let current = observed
+            .buckets
+            .iter()
+            .find(|b| b.id == bucket.get_id())
+            .map(|b| &b.permissions);
+        if current != Some(&permissions) {
+            ctx.garage_client
+                .set_bucket_permissions(bucket.get_id(), observed.id.clone(), permissions)
+                .await
+                .map_err(Error::GarageClientError)?;
+            changes.push("bucket permissions".to_string());
         }
-    })
-    .await
-    .map_err(|e| Error::FinalizerError(Box::new(e)))
-}
 
-fn error_policy(token: Arc<Token>, error: &Error, ctx: Arc<Context>) -> Action {
-    error!("reconcile failed: {:?}", error);
-    let oref = token.object_ref(&());
-
-    ctx.metrics.reconcile.set_failure(
-        oref.api_version.unwrap(),
-        oref.kind.unwrap(),
-        token.name_any(),
-        error,
-    );
-    Action::requeue(Duration::from_secs(5 * 60))
+        // Adopted keys, or ones whose secret was deleted, get it published again.
+        let secrets: Api<Secret> =
+            Api::namespaced(ctx.kube_client.clone(), &self.namespace().unwrap());
+        if secrets
+            .get_opt(&self.spec().tokenSecretName)
+            .await
+            .map_err(Error::KubeError)?
+            .is_none()
+        {
+            let key = ctx
+                .garage_client
+                .get_key_with_secret(observed.id)
+                .await
+                .map_err(Error::GarageClientError)?
+                .ok_or_else(|| Error::NotReady(format!("key `{}`", self.spec().name)))?;
+            self.publish_secret(ctx, &key).await?;
+            changes.push("secret published".to_string());
+        }
+
+        Ok(changes)
+    }
+
+    async fn delete(&self, ctx: &Context) -> Result<()> {
+        ctx.garage_client
+            .delete_key(self.get_id())
+            .await
+            .map_err(Error::GarageClientError)
+    }
+
+    async fn orphan(&self, ctx: &Context, note: &str) -> Result<()> {
+        ctx.garage_client
+            .update_key_name(self.get_id(), format!("{} ({note})", self.spec().name))
+            .await
+            .map_err(Error::GarageClientError)
+    }
 }
 
-/// Initialize the controller and shared state (given the crd is installed)
-pub async fn run(context: Arc<Context>, kube_client: KubeClient) -> Result<(), Error> {
-    let tokens = Api::<Token>::all(kube_client.clone());
-    match tokens.list(&ListParams::default().limit(1)).await {
-        Err(err) => return Err(Error::MissingCrds(err)),
-        Ok(_) => info!("CRDs for Token are installed!"),
-    };
-
-    info!("Starting controller");
-
-    Controller::new(tokens, Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
-        .await;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn key_name_marker() {
+        let uid = "0b7f4a8e-7a4c-4a5e-9d0e-2f1c3b4a5d6e";
+        assert_eq!(split_marker(&marked_name("app", uid)), ("app", Some(uid)));
+        assert_eq!(split_marker("app"), ("app", None));
+        assert_eq!(
+            split_marker("app (orphaned by console.tjo.cloud)"),
+            ("app (orphaned by console.tjo.cloud)", None)
+        );
+    }
 }
@@ -1,16 +1,124 @@
+use crate::{Error, Result, reconciler::DeletionPolicy};
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[allow(unused)]
 pub struct Postgresql {
+    /// Where the controller connects to as the admin user.
     pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Host applications connect to, published in login secrets, defaults to `host`.
+    #[serde(default)]
+    pub public_host: Option<String>,
+    /// Port applications connect to, published in login secrets, defaults to `port`.
+    #[serde(default)]
+    pub public_port: Option<u16>,
     pub user: String,
     pub password: String,
     pub sslmode: String,
     pub database: String,
     pub ssl_accept_invalid_cert: bool,
+    /// Maximum number of admin connections kept open to the server.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "default_connect_timeout_seconds")]
+    pub connect_timeout_seconds: u64,
+    #[serde(default = "default_statement_timeout_seconds")]
+    pub statement_timeout_seconds: u64,
+    #[serde(default = "default_health_check_interval_seconds")]
+    pub health_check_interval_seconds: u64,
+    /// Admin connections kept open to each managed database, for work inside of it.
+    #[serde(default = "default_database_pool_size")]
+    pub database_pool_size: usize,
+    /// Admin connections to managed databases, over all of them.
+    #[serde(default = "default_max_database_connections")]
+    pub max_database_connections: usize,
+    /// Connections to a managed database are closed after it wasn't used for this long.
+    #[serde(default = "default_database_idle_timeout_seconds")]
+    pub database_idle_timeout_seconds: u64,
+    /// Extensions databases on this server may list, they are created by the admin user.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// PEM certificate clients verify the server with, published as `ca.crt` in login secrets.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    /// Namespaces that may use this server, without it every namespace can.
+    #[serde(default)]
+    pub access: Option<AccessPolicy>,
+}
+
+/// Which namespaces may use a backend, a namespace has to be listed or match one of the selectors.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Sets of labels, a namespace carrying all labels of any one set is allowed.
+    #[serde(default)]
+    pub namespace_selectors: Vec<BTreeMap<String, String>>,
+}
+
+impl AccessPolicy {
+    /// Whether the namespace labels are needed to decide, listed namespaces are allowed without them.
+    pub fn needs_labels(&self, namespace: &str) -> bool {
+        !self.namespaces.iter().any(|allowed| allowed == namespace)
+            && !self.namespace_selectors.is_empty()
+    }
+
+    pub fn allows(&self, namespace: &str, labels: &BTreeMap<String, String>) -> bool {
+        self.namespaces.iter().any(|allowed| allowed == namespace)
+            || self.namespace_selectors.iter().any(|selector| {
+                selector
+                    .iter()
+                    .all(|(key, value)| labels.get(key) == Some(value))
+            })
+    }
+}
+
+impl Postgresql {
+    pub fn public_host(&self) -> &str {
+        self.public_host.as_deref().unwrap_or(&self.host)
+    }
+
+    pub fn public_port(&self) -> u16 {
+        self.public_port.unwrap_or(self.port)
+    }
+}
+
+fn default_port() -> u16 {
+    5432
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+fn default_database_pool_size() -> usize {
+    2
+}
+
+fn default_max_database_connections() -> usize {
+    8
+}
+
+fn default_database_idle_timeout_seconds() -> u64 {
+    300
+}
+
+fn default_connect_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_statement_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_health_check_interval_seconds() -> u64 {
+    15
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +126,25 @@ pub struct Postgresql {
 pub struct S3 {
     pub url: String,
     pub token: String,
+    /// S3 API with the service key used to read and write backups.
+    pub api: Option<S3Api>,
+    /// Namespaces that may use buckets and keys, without it every namespace can.
+    #[serde(default)]
+    pub access: Option<AccessPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct S3Api {
+    pub url: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+fn default_s3_region() -> String {
+    "garage".to_string()
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,6 +152,47 @@ pub struct S3 {
 pub struct Settings {
     pub s3: S3,
     pub postgresql: HashMap<String, Postgresql>,
+    /// Used for resources that don't set `deletionPolicy` themselves.
+    #[serde(default)]
+    pub deletion_policy: DeletionPolicy,
+    #[serde(default)]
+    pub naming: NamingPolicy,
+}
+
+/// How names on resources become names on the backends, where databases, roles and buckets share one namespace.
+///
+/// `{namespace}` and `{name}` are replaced, as is `{hash}` with a short hash of the namespace,
+/// for names that are unique without giving the namespace away.
+/// Objects keep the name they were created under when the policy changes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct NamingPolicy(String);
+
+impl Default for NamingPolicy {
+    fn default() -> Self {
+        NamingPolicy("{name}".to_string())
+    }
+}
+
+impl NamingPolicy {
+    /// Name on the backend for `name` in `namespace`, which has to fit in 63 bytes.
+    pub fn apply(&self, namespace: &str, name: &str) -> Result<String> {
+        let hash: String = Sha256::digest(namespace.as_bytes())
+            .iter()
+            .take(4)
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let applied = self
+            .0
+            .replace("{namespace}", namespace)
+            .replace("{name}", name)
+            .replace("{hash}", &hash);
+
+        if applied.len() > 63 {
+            return Err(Error::IllegalName(applied));
+        }
+        Ok(applied)
+    }
 }
 
 impl Settings {
@@ -50,3 +218,47 @@ impl Settings {
         &self.s3
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn naming_policy() {
+        assert_eq!(
+            NamingPolicy::default().apply("team", "example").unwrap(),
+            "example"
+        );
+        let prefixed = NamingPolicy("{namespace}-{name}".to_string());
+        assert_eq!(prefixed.apply("team", "example").unwrap(), "team-example");
+        assert!(prefixed.apply(&"a".repeat(60), "example").is_err());
+
+        let hashed = NamingPolicy("{name}-{hash}".to_string());
+        let name = hashed.apply("team", "example").unwrap();
+        assert_eq!(name.len(), "example-".len() + 8);
+        assert_ne!(name, hashed.apply("other", "example").unwrap());
+    }
+
+    #[test]
+    fn access_policy() {
+        let policy = AccessPolicy {
+            namespaces: vec!["platform".to_string()],
+            namespace_selectors: vec![BTreeMap::from([
+                ("tjo.cloud/tenant".to_string(), "true".to_string()),
+                ("tjo.cloud/tier".to_string(), "paid".to_string()),
+            ])],
+        };
+        let tenant = BTreeMap::from([
+            ("tjo.cloud/tenant".to_string(), "true".to_string()),
+            ("tjo.cloud/tier".to_string(), "paid".to_string()),
+        ]);
+        let free = BTreeMap::from([("tjo.cloud/tenant".to_string(), "true".to_string())]);
+
+        assert!(!policy.needs_labels("platform"));
+        assert!(policy.allows("platform", &BTreeMap::new()));
+        assert!(policy.needs_labels("shop"));
+        assert!(policy.allows("shop", &tenant));
+        assert!(!policy.allows("shop", &free));
+        assert!(!AccessPolicy::default().allows("shop", &tenant));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{
-    Context, GarageClient, Metrics, Settings, resources::postgresql::Client as PostgresqlClient,
+    Context, GarageClient, Metrics, S3Client, Settings,
+    backoff::Backoff,
+    resources::postgresql::{DatabasePools, Health as PostgresqlHealth, Pool as PostgresqlPool},
 };
 use chrono::{DateTime, Utc};
 use kube::{
@@ -42,13 +44,30 @@ pub struct State {
     metrics: Arc<Metrics>,
     /// Settings
     settings: Arc<Settings>,
+    /// Postgresql connection pools, one per configured server
+    postgresql_clients: Arc<HashMap<String, PostgresqlPool>>,
+    /// Postgresql connection pools for managed databases, opened on demand
+    postgresql_databases: Arc<DatabasePools>,
 }
 
 /// State wrapper around the controller outputs for the web server
 impl State {
     pub fn new(settings: Settings) -> State {
+        let postgresql_clients = settings
+            .postgresql()
+            .iter()
+            .map(|(name, server)| {
+                (
+                    name.clone(),
+                    PostgresqlPool::new(name.clone(), server.clone()),
+                )
+            })
+            .collect();
+
         State {
+            postgresql_databases: Arc::new(DatabasePools::new(settings.postgresql())),
             settings: Arc::new(settings),
+            postgresql_clients: Arc::new(postgresql_clients),
             diagnostics: Arc::new(RwLock::new(Diagnostics::default())),
             metrics: Arc::new(Metrics::default()),
         }
@@ -67,6 +86,24 @@ impl State {
         buffer
     }
 
+    /// Postgresql pools getter
+    pub fn postgresql_clients(&self) -> Arc<HashMap<String, PostgresqlPool>> {
+        self.postgresql_clients.clone()
+    }
+
+    /// Postgresql database pools getter
+    pub fn postgresql_databases(&self) -> Arc<DatabasePools> {
+        self.postgresql_databases.clone()
+    }
+
+    /// Health of every configured Postgresql server
+    pub fn postgresql_health(&self) -> HashMap<String, PostgresqlHealth> {
+        self.postgresql_clients
+            .iter()
+            .map(|(name, pool)| (name.clone(), pool.health()))
+            .collect()
+    }
+
     /// State getter
     pub async fn diagnostics(&self) -> Diagnostics {
         self.diagnostics.read().await.clone()
@@ -76,8 +113,8 @@ impl State {
     pub async fn to_context(
         &self,
         kube_client: KubeClient,
-        postgresql_clients: Arc<HashMap<String, PostgresqlClient>>,
         garage_client: Arc<GarageClient>,
+        s3_client: Option<Arc<S3Client>>,
     ) -> Arc<Context> {
         Arc::new(Context {
             kube_client: kube_client.clone(),
@@ -86,7 +123,10 @@ impl State {
             diagnostics: self.diagnostics.clone(),
             settings: self.settings.clone(),
             garage_client,
-            postgresql_clients,
+            s3_client,
+            postgresql_clients: self.postgresql_clients.clone(),
+            postgresql_databases: self.postgresql_databases.clone(),
+            backoff: Arc::new(Backoff::default()),
         })
     }
 }